@@ -0,0 +1,107 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bl8_tui_rs::cell::Cell;
+use bl8_tui_rs::engine::{INITIAL_STEP_COUNT, SEQ_TRACK_COUNT};
+use bl8_tui_rs::history::{Command, History, MAX_REVISIONS};
+
+// counts live heap bytes so the benchmark can report how much history keeps
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const EDITS: usize = 100_000;
+
+fn edit(step: usize) -> Vec<Command> {
+    vec![Command::Insert {
+        x: step % (SEQ_TRACK_COUNT * 3),
+        y: step * 7 % INITIAL_STEP_COUNT,
+        cell: Cell::Param((step % 100) as u8),
+    }]
+}
+
+fn apply(history: &mut History, step: usize) {
+    history.apply(edit(step));
+    // the engine normally takes these off the channel
+    history.channel.1.try_iter().for_each(drop);
+}
+
+fn memory(_: &mut Criterion) {
+    let start = ALLOCATED.load(Ordering::Relaxed);
+    let mut history = History::new();
+    let grid = ALLOCATED.load(Ordering::Relaxed) - start;
+    let mut samples = vec![];
+    for step in 0..EDITS {
+        apply(&mut history, step);
+        if (step + 1) % (EDITS / 10) == 0 {
+            samples.push(ALLOCATED.load(Ordering::Relaxed) - start);
+        }
+    }
+
+    for (idx, bytes) in samples.iter().enumerate() {
+        println!(
+            "history/memory: {:>6} edits {:>9} bytes (snapshots would need {} bytes)",
+            (idx + 1) * EDITS / 10,
+            bytes,
+            grid * (idx + 1) * EDITS / 10
+        );
+    }
+    // once the revision limit is reached memory stops growing
+    let limit = samples[MAX_REVISIONS * 2 / (EDITS / 10)];
+    assert!(
+        samples.iter().all(|&bytes| bytes <= limit * 2),
+        "history memory keeps growing: {:?}",
+        samples
+    );
+}
+
+fn edits(c: &mut Criterion) {
+    let mut history = History::new();
+    for step in 0..MAX_REVISIONS {
+        apply(&mut history, step);
+    }
+
+    let mut step = 0;
+    c.bench_function("history/apply", |b| {
+        b.iter(|| {
+            step += 1;
+            apply(&mut history, step);
+        })
+    });
+    c.bench_function("history/undo_redo", |b| {
+        b.iter(|| {
+            history.undo();
+            history.redo();
+            history.channel.1.try_iter().for_each(drop);
+        })
+    });
+    c.bench_function("history/to_state", |b| {
+        b.iter(|| History::to_state(black_box(history.get_grid())))
+    });
+}
+
+criterion_group!(benches, memory, edits);
+criterion_main!(benches);
@@ -1,23 +1,83 @@
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use anyhow::Context;
+use cpal::traits::DeviceTrait;
+use cpal::{StreamConfig, StreamError};
 use crossbeam::channel::*;
 use crossterm::{
     cursor,
-    event::{poll, read, Event, KeyCode},
+    event::{poll, read, Event, KeyCode, KeyEvent},
     queue,
-    style::{self, Stylize},
+    style::{ContentStyle, Stylize},
     terminal::{self, disable_raw_mode, enable_raw_mode},
 };
+use regex::Regex;
 use std::{
-    io::{stdout, Result, Write},
-    time::Duration,
+    collections::HashMap,
+    io::{stdout, Result},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
-use crate::engine::Engine;
-use crate::history::{Grid, History, PITCHES};
+use crate::audio::{self, Backend, Driver, NullOutput, Output};
+use crate::block::{self, Block};
+use crate::cell::{self, Cell, CellKind, Fx, MAX_VALUE};
+use crate::commands::{self, ExCommand, Range, Steps, Substitute};
+use crate::config::{AudioConfig, Colors, Config, KeyRemaps};
+use crate::engine::{
+    Control, Engine, Param, DEFAULT_BPM, DEFAULT_ENGINE, DRUM_TRACK_COUNT, ENGINE_COUNT, MAX_BPM,
+    MAX_STEP_COUNT, MIN_BPM, PARAMS, PARAM_COUNT, SEQ_TRACK_COUNT,
+};
+use crate::export::{self, Fader, Stems};
+use crate::history::{Command, Grid, History};
+#[cfg(feature = "jack")]
+use crate::jack_output::JackOutput;
+use crate::keys::{Action, KeyCommand, KeyParser, Motion, Parsed};
+use crate::project::Project;
+use crate::recorder::{Recorder, Tap};
+use crate::registers::Registers;
+use crate::screen::Screen;
 
-pub const SAMPLE_RATE: f32 = 48000.0;
+// when exporting without an output device
+const EXPORT_SAMPLE_RATE: u32 = 48000;
 const CELL_WIDTH: usize = 4;
 const CELL_HEIGHT: usize = 1;
+const TRACK_COLUMNS: usize = 3;
+const BEAT_LENGTH: usize = 4;
+const DEFAULT_OCTAVE: i32 = 3;
+const DEFAULT_PARAM: u8 = 50;
+const MAX_EDIT_STEP: usize = 16;
+const STATUS_COLUMN: u16 = 24;
+const MAX_MACRO_DEPTH: usize = 100;
+// keys a macro may replay, counting those of the macros it plays
+const MAX_MACRO_KEYS: usize = 100_000;
+const MAX_OCTAVE: i32 = 8;
+// the highest note a piano key can be mapped to, two octaves up
+const MAX_PIANO_NOTE: i32 = 24;
+// how often a lost audio device is looked for again
+const AUDIO_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+// room for the step numbers left of the grid
+const ROW_NUMBER_WIDTH: usize = 4;
+// bottom two qwerty rows laid out like a piano keyboard, as semitones above
+// the current octave's C
+const PIANO_KEYS: [(char, i32); 17] = [
+    ('z', 0),
+    ('s', 1),
+    ('x', 2),
+    ('d', 3),
+    ('c', 4),
+    ('v', 5),
+    ('g', 6),
+    ('b', 7),
+    ('h', 8),
+    ('n', 9),
+    ('j', 10),
+    ('m', 11),
+    (',', 12),
+    ('l', 13),
+    ('.', 14),
+    (';', 15),
+    ('/', 16),
+];
 
 #[derive(Clone, Copy)]
 enum EditingMode {
@@ -27,10 +87,11 @@ enum EditingMode {
     Command,
 }
 
-#[derive(Clone)]
-pub enum Command {
-    Insert { x: usize, y: usize, input: String },
-    Delete { x: usize, y: usize },
+// the last change, kept around so it can be repeated with `.`
+#[derive(Clone, Copy)]
+enum Change {
+    Normal(KeyCommand),
+    Block(KeyCommand, (usize, usize)),
 }
 
 pub struct App {
@@ -38,11 +99,76 @@ pub struct App {
     y: usize,
     active_step: i8,
     mode: EditingMode,
-    register: Option<String>,
+    registers: Registers,
+    keys: KeyParser,
+    // insert mode keys and the semitone they play
+    piano: Vec<(char, i32)>,
+    last_change: Option<Change>,
+    recording: Option<(char, Vec<Event>)>,
+    macros: HashMap<char, Vec<Event>>,
+    last_macro: Option<char>,
+    macro_depth: usize,
+    // keys the outermost macro replayed so far, and whether it has to stop
+    macro_keys: usize,
+    macro_abort: bool,
+    messages: Vec<String>,
+    error: Option<String>,
     cmd_line: String,
+    cmd_history: Vec<String>,
+    cmd_history_pos: Option<usize>,
     curr_input: Vec<char>,
+    octave: i32,
+    edit_step: usize,
     history: History,
     selection: Option<(usize, usize)>,
+    last_visual: Option<((usize, usize), (usize, usize))>,
+    last_search: Option<Regex>,
+    path: Option<PathBuf>,
+    // the project as last written or loaded, to tell whether :q and :e would
+    // throw changes away
+    saved: Option<Project>,
+    bpm: f32,
+    engines: [usize; SEQ_TRACK_COUNT],
+    // the output pair of every track, counted from zero
+    routes: [usize; SEQ_TRACK_COUNT],
+    control: (Sender<Control>, Receiver<Control>),
+    // terminal size, and the first track and row shown on screen
+    size: (u16, u16),
+    screen: Screen,
+    scroll: (usize, usize),
+    follow: bool,
+    hex: bool,
+    // tweaks lock their value on the playing step
+    record: bool,
+    // what every track plays with unless a step locks another value, in the
+    // order of the track's columns: morph on the pitch column, harmonics, timbre
+    params: [[u8; TRACK_COLUMNS]; SEQ_TRACK_COUNT],
+    // the parameters that glide between locked values, saved with the project
+    glide: [[bool; PARAM_COUNT]; SEQ_TRACK_COUNT],
+    colors: Colors,
+    remaps: KeyRemaps,
+    audio: AudioConfig,
+    // without a stream editing still works, nothing plays until a device is
+    // back
+    stream: Option<Output>,
+    // the device and settings the stream was opened with
+    output: Option<(String, StreamConfig)>,
+    // the settings of the last attempt to open a stream
+    opened: Option<AudioConfig>,
+    last_audio_check: Instant,
+    // the default device is looked up on a thread of its own, listing
+    // devices can block for a while on some hosts
+    default_device: Option<Receiver<Option<String>>>,
+    // playhead positions from the engine
+    steps: Receiver<i8>,
+    stream_errors: (Sender<StreamError>, Receiver<StreamError>),
+    // the output being recorded with :rec, and the way to hand the audio
+    // thread its end of it
+    capture: Option<Recorder>,
+    taps: (Sender<Option<Tap>>, Receiver<Option<Tap>>),
+    // the directory an :export renders to on a thread of its own, and where
+    // it reports back
+    exporting: Option<(PathBuf, Receiver<anyhow::Result<Stems>>)>,
     exit: bool,
 }
 
@@ -50,14 +176,58 @@ impl App {
     pub fn new() -> App {
         App {
             x: 0,
-            y: 0,
+            y: 1,
             active_step: 0,
             mode: EditingMode::Normal,
-            register: None,
+            registers: Registers::new(),
+            keys: KeyParser::new(),
+            piano: PIANO_KEYS.to_vec(),
+            last_change: None,
+            recording: None,
+            macros: HashMap::new(),
+            last_macro: None,
+            macro_depth: 0,
+            macro_keys: 0,
+            macro_abort: false,
+            messages: vec![],
+            error: None,
             cmd_line: String::from(""),
+            cmd_history: vec![],
+            cmd_history_pos: None,
             curr_input: vec![],
+            octave: DEFAULT_OCTAVE,
+            edit_step: 1,
             history: History::new(),
             selection: None,
+            last_visual: None,
+            last_search: None,
+            path: None,
+            saved: None,
+            bpm: DEFAULT_BPM,
+            engines: [DEFAULT_ENGINE; SEQ_TRACK_COUNT],
+            routes: [0; SEQ_TRACK_COUNT],
+            control: crossbeam::channel::unbounded(),
+            size: terminal::size().unwrap_or((80, 24)),
+            screen: Screen::new(terminal::size().unwrap_or((80, 24))),
+            scroll: (0, 0),
+            follow: false,
+            hex: false,
+            record: false,
+            params: [[DEFAULT_PARAM; TRACK_COLUMNS]; SEQ_TRACK_COUNT],
+            glide: [[false; PARAM_COUNT]; SEQ_TRACK_COUNT],
+            colors: Colors::default(),
+            remaps: KeyRemaps::default(),
+            audio: AudioConfig::default(),
+            stream: None,
+            output: None,
+            opened: None,
+            last_audio_check: Instant::now(),
+            default_device: None,
+            steps: crossbeam::channel::never(),
+            stream_errors: crossbeam::channel::unbounded(),
+            capture: None,
+            taps: crossbeam::channel::unbounded(),
+            exporting: None,
             exit: false,
         }
     }
@@ -66,312 +236,1999 @@ impl App {
         self.history.get_grid()
     }
 
-    fn update_input_line(&self) -> Result<()> {
-        let mut stdout = stdout();
-        queue!(stdout, cursor::MoveTo(0, 17))?;
-        print!("{}", self.cmd_line);
-        queue!(stdout, cursor::MoveTo(self.x as u16, self.y as u16))?;
-        Ok(())
+    fn update_input_line(&mut self) {
+        let status = self.status_row();
+        let plain = ContentStyle::new();
+        match &self.error {
+            Some(error) if !matches!(self.mode, EditingMode::Command) => {
+                self.screen.print(0, status, error, plain.red());
+            }
+            _ => self.screen.print(0, status, &self.cmd_line, plain),
+        }
+        if !matches!(self.mode, EditingMode::Command) {
+            let mut text = format!(
+                "octave {}  step {}  {}",
+                self.octave,
+                self.edit_step,
+                self.keys.pending()
+            );
+            if let Some((name, _)) = self.recording {
+                text.push_str(&format!("  recording @{}", name));
+            }
+            if self.record {
+                text.push_str("  record");
+            }
+            if self.capture.is_some() {
+                text.push_str("  rec");
+            }
+            if self.exporting.is_some() {
+                text.push_str("  exporting");
+            }
+            if self.stream.is_none() {
+                text.push_str("  no audio");
+            } else if self.audio.backend == Backend::Jack && self.audio.transport {
+                // stopped until something rolls the transport
+                text.push_str("  jack transport");
+            }
+            self.screen.print(STATUS_COLUMN, status, &text, plain);
+        }
+        for (idx, message) in self.messages.iter().enumerate() {
+            self.screen
+                .print(0, status + 1 + idx as u16, message, plain);
+        }
+        let (x, y) = match self.mode {
+            EditingMode::Command => (self.cmd_line.chars().count() as u16, status),
+            _ => self.screen_position(self.col(), self.row()),
+        };
+        self.screen.set_cursor(x, y);
     }
 
     fn align_cursor_to_grid(&mut self) {
         self.x = (self.x / CELL_WIDTH) * CELL_WIDTH;
     }
 
-    fn draw(&mut self) -> Result<()> {
-        let mut stdout = stdout();
+    // number of steps and tracks that fit on screen, the header takes the
+    // first line and the status line and messages go below the grid
+    fn visible_rows(&self) -> usize {
+        let rows = (self.size.1 as usize).saturating_sub(2 + self.messages.len());
+        rows.clamp(1, self.get_grid()[0].len())
+    }
 
-        // clear the terminal
-        queue!(stdout, terminal::Clear(terminal::ClearType::All))?;
+    fn visible_tracks(&self) -> usize {
+        let width = (self.size.0 as usize).saturating_sub(ROW_NUMBER_WIDTH);
+        let tracks = width / (TRACK_COLUMNS * CELL_WIDTH);
+        tracks.clamp(1, SEQ_TRACK_COUNT)
+    }
 
-        queue!(stdout, cursor::MoveTo(0, 0))?;
-        print!("KICK        SNARE       HIHAT");
-        for (x, track) in self.get_grid().iter().enumerate() {
-            for (y, cell) in track.iter().enumerate() {
-                let y = y + 1;
-                let x = x * CELL_WIDTH;
-                queue!(stdout, cursor::MoveTo(x as u16, y as u16))?;
-                print!("{}", cell);
+    fn status_row(&self) -> u16 {
+        (self.visible_rows() + 1) as u16
+    }
+
+    fn screen_position(&self, col: usize, row: usize) -> (u16, u16) {
+        let x = ROW_NUMBER_WIDTH + (col - self.scroll.0 * TRACK_COLUMNS) * CELL_WIDTH;
+        (x as u16, (row - self.scroll.1 + 1) as u16)
+    }
+
+    fn update_scroll(&mut self) {
+        // keep the cursor on screen, or the playhead when following playback
+        let (rows, tracks) = (self.visible_rows(), self.visible_tracks());
+        let len = self.get_grid()[0].len();
+        let (track, mut row) = (self.col() / TRACK_COLUMNS, self.row());
+        if self.follow {
+            row = (self.active_step.max(0) as usize).min(len - 1);
+            // page instead of scrolling step by step, like most trackers
+            if !(self.scroll.1..self.scroll.1 + rows).contains(&row) {
+                self.scroll.1 = row;
             }
+        }
+        self.scroll.0 = self
+            .scroll
+            .0
+            .clamp((track + 1).saturating_sub(tracks), track);
+        self.scroll.1 = self.scroll.1.clamp((row + 1).saturating_sub(rows), row);
+        self.scroll.1 = self.scroll.1.min(len - rows);
+        if self.follow {
+            // the cursor moves along with the page
+            let cursor = self.row().clamp(self.scroll.1, self.scroll.1 + rows - 1);
+            self.y = cursor + 1;
+        }
+    }
 
-            for _ in 0..CELL_WIDTH {
-                let x = x * CELL_WIDTH;
-                queue!(
-                    stdout,
-                    cursor::MoveTo(x as u16, (self.active_step + 1) as u16),
-                    style::PrintStyledContent("░".dark_magenta())
-                )?;
+    fn track_name(&self, track: usize) -> String {
+        match track {
+            0 => "KICK".to_string(),
+            1 => "SNARE".to_string(),
+            2 => "HIHAT".to_string(),
+            _ => format!("SYNTH{} E{:02}", track + 1, self.engines[track]),
+        }
+    }
+
+    fn draw(&mut self) -> Result<()> {
+        let mut stdout = stdout();
+        self.update_scroll();
+        self.screen.clear();
+        let plain = ContentStyle::new();
+
+        let (rows, tracks) = (self.visible_rows(), self.visible_tracks());
+        let columns = self.scroll.0 * TRACK_COLUMNS..(self.scroll.0 + tracks) * TRACK_COLUMNS;
+        let steps = self.scroll.1..self.scroll.1 + rows;
+        let width = ROW_NUMBER_WIDTH + tracks * TRACK_COLUMNS * CELL_WIDTH;
+        for (track, &color) in self
+            .colors
+            .tracks
+            .iter()
+            .enumerate()
+            .skip(self.scroll.0)
+            .take(tracks)
+        {
+            let (x, _) = self.screen_position(track * TRACK_COLUMNS, self.scroll.1);
+            let name = self.track_name(track);
+            let style = plain.with(color);
+            let style = if track == self.col() / TRACK_COLUMNS {
+                style.reverse().bold()
+            } else {
+                style
+            };
+            self.screen.print(x, 0, &name, style);
+        }
+        for y in steps.clone() {
+            // hex steps count from 0 like most trackers, decimal steps from 1
+            // like the ranges on the command line
+            let number = if self.hex {
+                format!("{:02X}", y)
+            } else {
+                format!("{:>3}", y + 1)
+            };
+            let (_, row) = self.screen_position(columns.start, y);
+            let style = if y % BEAT_LENGTH == 0 {
+                plain.bold()
+            } else {
+                plain.with(self.colors.empty)
+            };
+            self.screen.print(0, row, &number, style);
+        }
+        for x in columns.clone() {
+            let color = self.colors.tracks[x / TRACK_COLUMNS];
+            for y in steps.clone() {
+                let cell = self.get_grid()[x][y];
+                let (col, row) = self.screen_position(x, y);
+                let style = match cell {
+                    Cell::Empty => plain.with(self.colors.empty),
+                    _ => plain.with(color),
+                };
+                let style = if self.is_selected(x, y) {
+                    style.reverse()
+                } else {
+                    style
+                };
+                self.screen.print(col, row, &cell.to_string(), style);
+            }
+        }
+        // beats and the playhead are highlighted across the whole row
+        for y in steps.clone() {
+            let (_, row) = self.screen_position(columns.start, y);
+            if y == self.active_step.max(0) as usize {
+                self.screen
+                    .style(0, row, width, |style| style.on(self.colors.playhead));
+            } else if y % BEAT_LENGTH == 0 {
+                self.screen
+                    .style(0, row, width, |style| style.on(self.colors.beat));
             }
         }
 
-        queue!(stdout, cursor::MoveTo(self.x as u16, self.y as u16))?;
+        // show a value that is still being typed in place of the cell
+        if !self.curr_input.is_empty() {
+            let (x, y) = self.screen_position(self.col(), self.row());
+            let input = format!(
+                "{:<1$}",
+                self.curr_input.iter().collect::<String>(),
+                CELL_WIDTH
+            );
+            self.screen.print(x, y, &input, plain);
+        }
 
         match self.mode {
             EditingMode::Normal => {
-                queue!(stdout, cursor::SetCursorStyle::SteadyBlock).unwrap();
+                queue!(stdout, cursor::SetCursorStyle::SteadyBlock)?;
                 self.cmd_line = "-- NORMAL --".to_string();
             }
             EditingMode::Insert => {
-                queue!(stdout, cursor::SetCursorStyle::SteadyBar).unwrap();
+                queue!(stdout, cursor::SetCursorStyle::SteadyBar)?;
                 self.cmd_line = "-- INSERT --".to_string();
             }
             EditingMode::Visual => {
-                queue!(stdout, cursor::SetCursorStyle::SteadyBlock).unwrap();
+                queue!(stdout, cursor::SetCursorStyle::SteadyBlock)?;
                 self.cmd_line = "-- VISUAL --".to_string();
             }
             EditingMode::Command => {
-                queue!(stdout, cursor::SetCursorStyle::SteadyBar).unwrap();
+                queue!(stdout, cursor::SetCursorStyle::SteadyBar)?;
             }
         }
-        self.update_input_line()?;
+        self.update_input_line();
 
-        stdout.flush()?;
-        Ok(())
+        self.screen.flush(&mut stdout)
     }
 
     fn process_key(&mut self, key: Event) {
-        match key {
-            Event::Key(event) => match (self.mode, event.code) {
-                (EditingMode::Normal | EditingMode::Visual, KeyCode::Char(ch)) => {
-                    self.align_cursor_to_grid();
-                    self.curr_input.clear();
-                    match ch {
-                        'h' => {
-                            if self.x > 0 {
-                                self.x -= CELL_WIDTH;
-                            } else {
-                                self.x = self.get_grid().len() * CELL_WIDTH - CELL_WIDTH;
-                            }
-                        }
-                        'j' => {
-                            if self.y + CELL_HEIGHT < self.get_grid()[self.x / CELL_WIDTH].len() {
-                                self.y += CELL_HEIGHT;
-                            } else {
-                                self.y = 0;
-                            }
-                        }
-                        'k' => {
-                            if self.y > 0 {
-                                self.y -= CELL_HEIGHT;
-                            } else {
-                                self.y = self.get_grid()[self.x / CELL_WIDTH].len() - CELL_HEIGHT;
-                            }
-                        }
-                        'l' => {
-                            if self.x + CELL_WIDTH < self.get_grid().len() * CELL_WIDTH {
-                                self.x += CELL_WIDTH;
-                            } else {
-                                self.x = 0;
-                            }
-                        }
-                        'u' => {
-                            self.history.undo();
-                        }
-                        'r' => {
-                            self.history.redo();
-                        }
-                        'x' => {
-                            self.yank();
-                            let cmd = Command::Delete {
-                                x: self.x / CELL_WIDTH,
-                                y: self.y,
-                            };
-                            self.apply(cmd);
-                        }
-                        ':' => {
-                            self.cmd_line = ":".to_string();
-                            self.mode = EditingMode::Command;
-                        }
-                        'i' => {
-                            self.mode = EditingMode::Insert;
-                        }
-                        'y' => {
-                            self.yank();
+        if let Event::Resize(width, height) = key {
+            self.size = (width, height);
+            self.screen.resize(self.size);
+            return;
+        }
+        if !matches!(key, Event::Key(_)) {
+            return;
+        }
+        self.messages.clear();
+        self.error = None;
+        // keys replayed from a macro are not recorded a second time
+        if self.macro_depth == 0 {
+            if let Some((_, events)) = &mut self.recording {
+                events.push(key.clone());
+            }
+        }
+        // remapped keys are handled as if the replacement had been typed
+        if let Event::Key(event) = key {
+            if let KeyCode::Char(ch) = event.code {
+                let remaps = match self.mode {
+                    EditingMode::Normal => Some(&self.remaps.normal),
+                    EditingMode::Visual => Some(&self.remaps.visual),
+                    EditingMode::Insert => Some(&self.remaps.insert),
+                    EditingMode::Command => None,
+                };
+                if let Some(keys) = remaps.and_then(|remaps| remaps.get(&ch)).cloned() {
+                    for ch in keys.chars() {
+                        self.handle_key(KeyEvent::new(KeyCode::Char(ch), event.modifiers));
+                    }
+                    return;
+                }
+            }
+            self.handle_key(event);
+        }
+    }
+
+    fn handle_key(&mut self, event: KeyEvent) {
+        match (self.mode, event.code) {
+            (EditingMode::Normal | EditingMode::Visual, KeyCode::Char(ch)) => {
+                self.align_cursor_to_grid();
+                self.curr_input.clear();
+                let visual = matches!(self.mode, EditingMode::Visual);
+                if ch == 'q' && !visual && self.keys.pending().is_empty() {
+                    if let Some((name, mut events)) = self.recording.take() {
+                        // drop the q that stopped the recording
+                        events.pop();
+                        self.macros.insert(name, events);
+                        return;
+                    }
+                }
+                if let Parsed::Complete(cmd) = self.keys.push(ch, visual) {
+                    self.execute(cmd);
+                }
+            }
+            (EditingMode::Normal, KeyCode::Esc) => {
+                self.keys.clear();
+            }
+            (EditingMode::Insert, KeyCode::Char(ch)) => {
+                if self.sub_column() == 0 {
+                    self.insert_note(ch);
+                } else if ch.is_ascii_digit()
+                    || (self.curr_input.is_empty() && "vem".contains(ch.to_ascii_lowercase()))
+                {
+                    // a letter in front makes the value a velocity or fx command
+                    self.curr_input.push(ch.to_ascii_uppercase());
+
+                    // parameter values are two digits, commit once complete
+                    let digits = self.curr_input.iter().filter(|c| c.is_ascii_digit());
+                    if digits.count() == 2 {
+                        self.update_selected_cell();
+                        self.advance(self.edit_step);
+                    }
+                }
+            }
+            (EditingMode::Insert, KeyCode::Enter) => {
+                if !self.curr_input.is_empty() {
+                    self.update_selected_cell();
+                }
+                self.advance(self.edit_step);
+            }
+            (EditingMode::Insert, KeyCode::Backspace | KeyCode::Delete) => {
+                if self.curr_input.pop().is_none() {
+                    let cmd = Command::Delete {
+                        x: self.col(),
+                        y: self.row(),
+                    };
+                    self.apply(cmd);
+                }
+            }
+            (EditingMode::Insert, KeyCode::Esc) => {
+                if !self.curr_input.is_empty() {
+                    self.update_selected_cell();
+                }
+                self.history.commit();
+                self.mode = EditingMode::Normal;
+            }
+            (EditingMode::Command, KeyCode::Enter) => {
+                self.mode = EditingMode::Normal;
+                self.run_command();
+                self.cmd_line = String::from("");
+                self.cmd_history_pos = None;
+            }
+            (EditingMode::Command, KeyCode::Esc) => {
+                self.cmd_line = String::from("");
+                self.cmd_history_pos = None;
+                self.mode = EditingMode::Normal;
+            }
+            (EditingMode::Command, KeyCode::Char(c)) => {
+                self.cmd_line.push(c);
+            }
+            (EditingMode::Command, KeyCode::Backspace) => {
+                self.cmd_line.pop();
+                if self.cmd_line.is_empty() {
+                    self.cmd_history_pos = None;
+                    self.mode = EditingMode::Normal;
+                }
+            }
+            (EditingMode::Command, KeyCode::Tab) => {
+                let (line, candidates) = commands::complete(&self.cmd_line);
+                self.cmd_line = line;
+                if !candidates.is_empty() {
+                    self.messages = vec![candidates.join("  ")];
+                }
+            }
+            (EditingMode::Command, KeyCode::Up) => {
+                if !self.cmd_history.is_empty() {
+                    let pos = self
+                        .cmd_history_pos
+                        .map_or(self.cmd_history.len() - 1, |pos| pos.saturating_sub(1));
+                    self.cmd_history_pos = Some(pos);
+                    self.cmd_line = self.cmd_history[pos].clone();
+                }
+            }
+            (EditingMode::Command, KeyCode::Down) => match self.cmd_history_pos {
+                Some(pos) if pos + 1 < self.cmd_history.len() => {
+                    self.cmd_history_pos = Some(pos + 1);
+                    self.cmd_line = self.cmd_history[pos + 1].clone();
+                }
+                _ => {
+                    self.cmd_history_pos = None;
+                    self.cmd_line = ":".to_string();
+                }
+            },
+            (EditingMode::Visual, KeyCode::Esc) => {
+                self.keys.clear();
+                self.selection = None;
+                self.mode = EditingMode::Normal;
+            }
+            (_, _) => {}
+        }
+    }
+
+    fn col(&self) -> usize {
+        self.x / CELL_WIDTH
+    }
+
+    fn row(&self) -> usize {
+        // the first screen row holds the track headers
+        self.y - 1
+    }
+
+    fn sub_column(&self) -> usize {
+        // each track spans three columns: pitch, harmonics and timbre
+        self.col() % TRACK_COLUMNS
+    }
+
+    fn insert_note(&mut self, ch: char) {
+        // digits change the current octave, piano keys write a complete note
+        if ch.is_ascii_digit() {
+            // checked like :set octave so 9 is refused here too
+            if let Err(err) = self.set_option("octave", &ch.to_string()) {
+                self.error = Some(err.to_string());
+            }
+            return;
+        }
+        let Some(&(_, semitone)) = self.piano.iter().find(|(key, _)| *key == ch) else {
+            return;
+        };
+        let pitch = (self.octave + 1) * 12 + semitone;
+        if pitch > 127 {
+            return;
+        }
+
+        let cmd = Command::Insert {
+            x: self.col(),
+            y: self.row(),
+            cell: Cell::Pitch(pitch as u8),
+        };
+        self.apply(cmd);
+        self.advance(self.edit_step);
+    }
+
+    fn advance(&mut self, rows: usize) {
+        // move the cursor down, wrapping around at the end of the pattern
+        let len = self.get_grid()[self.col()].len();
+        self.y = (self.row() + rows * CELL_HEIGHT) % len + 1;
+    }
+
+    fn run_command(&mut self) {
+        let line = self.cmd_line.trim().to_string();
+        if line.len() <= 1 {
+            return;
+        }
+        self.cmd_history.retain(|prev| *prev != line);
+        self.cmd_history.push(line.clone());
+
+        let result = match line.strip_prefix('/') {
+            Some(pattern) => self.search(pattern),
+            None => self.execute_command(&line),
+        };
+        if let Err(err) = result {
+            self.error = Some(err.to_string());
+        }
+    }
+
+    fn search(&mut self, pattern: &str) -> anyhow::Result<()> {
+        self.last_search = Some(commands::search_pattern(pattern)?);
+        let (col, row) = self.find_match((self.col(), self.row()), true)?;
+        self.x = col * CELL_WIDTH;
+        self.y = row + 1;
+        Ok(())
+    }
+
+    fn find_match(&self, from: (usize, usize), forward: bool) -> anyhow::Result<(usize, usize)> {
+        // cells are searched step by step, reading each step left to right
+        let pattern = self
+            .last_search
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("no previous search pattern"))?;
+        let grid = self.get_grid();
+        let (cols, rows) = (grid.len(), grid[0].len());
+        let total = cols * rows;
+        let current = from.1 * cols + from.0;
+        for offset in 1..=total {
+            let idx = if forward {
+                (current + offset) % total
+            } else {
+                (current + total - offset) % total
+            };
+            let (col, row) = (idx % cols, idx / cols);
+            let cell = grid[col][row];
+            if !cell.is_empty() && pattern.is_match(&cell.to_string()) {
+                return Ok((col, row));
+            }
+        }
+        anyhow::bail!("pattern not found: {}", pattern)
+    }
+
+    fn execute_command(&mut self, line: &str) -> anyhow::Result<()> {
+        let cmd = ExCommand::parse(line)?;
+        let len = self.get_grid()[0].len();
+        // most commands act on the whole pattern unless given a range, a
+        // visual range also limits the columns they act on
+        let (columns, (start, end)) = match cmd.range {
+            Some(Range::Visual) => {
+                let ((x0, y0), (x1, y1)) = self
+                    .last_visual
+                    .ok_or_else(|| anyhow::anyhow!("no visual selection"))?;
+                (x0..x1 + 1, (y0, y1))
+            }
+            range => (
+                0..self.get_grid().len(),
+                range.unwrap_or(Range::All).rows(self.row(), len)?,
+            ),
+        };
+        // ex style deletes, yanks and substitutions default to the current step
+        let (line_start, line_end) = match cmd.range {
+            Some(_) => (start, end),
+            None => (self.row(), self.row()),
+        };
+
+        match cmd.name.as_str() {
+            "" => self.y = end + 1,
+            "q" | "quit" => {
+                self.check_saved(cmd.bang)?;
+                self.exit = true;
+            }
+            "w" => self.write(cmd.args.first())?,
+            "wq" => {
+                self.write(cmd.args.first())?;
+                self.exit = true;
+            }
+            "e" | "edit" => {
+                self.check_saved(cmd.bang)?;
+                self.edit(cmd.args.first())?;
+            }
+            "bpm" => self.set_option("bpm", &cmd.arg::<String>(0, "bpm")?)?,
+            "step" => self.set_option("step", &cmd.arg::<String>(0, "step")?)?,
+            "octave" => self.set_option("octave", &cmd.arg::<String>(0, "octave")?)?,
+            "set" => {
+                if cmd.args.is_empty() {
+                    self.messages = vec![format!(
+                        "bpm={}  step={}  octave={}  {}follow  {}hex  {}record  {}transport",
+                        self.bpm,
+                        self.edit_step,
+                        self.octave,
+                        if self.follow { "" } else { "no" },
+                        if self.hex { "" } else { "no" },
+                        if self.record { "" } else { "no" },
+                        if self.audio.transport { "" } else { "no" }
+                    )];
+                }
+                for arg in &cmd.args {
+                    // flags are switched on by name and off with a no prefix
+                    match arg.as_str() {
+                        "follow" => self.follow = true,
+                        "nofollow" => self.follow = false,
+                        "hex" => self.hex = true,
+                        "nohex" => self.hex = false,
+                        "record" => self.record = true,
+                        "norecord" => self.record = false,
+                        // the stream is opened again with the new setting
+                        "transport" => self.audio.transport = true,
+                        "notransport" => self.audio.transport = false,
+                        _ => {
+                            let (name, value) = arg
+                                .split_once('=')
+                                .ok_or_else(|| anyhow::anyhow!("expected option=value: {}", arg))?;
+                            self.set_option(name, value)?;
                         }
-                        'v' => {
-                            self.mode = EditingMode::Visual;
+                    }
+                }
+            }
+            "len" => {
+                let len = cmd.arg::<usize>(0, "length")?;
+                if !(1..=MAX_STEP_COUNT).contains(&len) {
+                    anyhow::bail!("length must be between 1 and {}", MAX_STEP_COUNT);
+                }
+                self.set_length(len);
+            }
+            "clear" => {
+                let track = match cmd.args.first() {
+                    Some(_) => Some(self.track_arg(&cmd, 0)?),
+                    None => None,
+                };
+                let columns =
+                    columns.filter(|col| track.is_none_or(|track| col / TRACK_COLUMNS == track));
+                self.change_rows(columns, (start, end), block::clear)?;
+            }
+            "interpolate" => {
+                // ramps between the locks on the first and last step of the
+                // range, pitches are left alone
+                let track = match cmd.args.first() {
+                    Some(_) => Some(self.track_arg(&cmd, 0)?),
+                    None => None,
+                };
+                let columns = columns.filter(|col| {
+                    col % TRACK_COLUMNS != 0
+                        && track.is_none_or(|track| col / TRACK_COLUMNS == track)
+                });
+                self.change_rows(columns, (start, end), block::interpolate)?;
+            }
+            "glide" => {
+                if cmd.args.is_empty() {
+                    self.messages = (0..SEQ_TRACK_COUNT)
+                        .filter(|&track| self.glide[track].contains(&true))
+                        .map(|track| {
+                            let params = PARAMS
+                                .iter()
+                                .zip(self.glide[track])
+                                .filter(|&(_, on)| on)
+                                .map(|(param, _)| param.name())
+                                .collect::<Vec<&str>>();
+                            format!("{:<12} {}", self.track_name(track), params.join(" "))
+                        })
+                        .collect();
+                    if self.messages.is_empty() {
+                        self.messages = vec!["no glide".to_string()];
+                    }
+                } else {
+                    // the track under the cursor unless one is given, the
+                    // parameters named glide and the others step
+                    let (track, names) = match cmd.args[0].parse::<usize>() {
+                        Ok(_) => (self.track_arg(&cmd, 0)?, &cmd.args[1..]),
+                        Err(_) => (self.col() / TRACK_COLUMNS, &cmd.args[..]),
+                    };
+                    let mut glide = [false; PARAM_COUNT];
+                    for name in names.iter().filter(|&name| name != "off") {
+                        glide[Param::from_name(name)? as usize] = true;
+                    }
+                    if track < DRUM_TRACK_COUNT && glide[Param::Morph as usize] {
+                        anyhow::bail!("{} has no morph", self.track_name(track));
+                    }
+                    self.set_glide(track, glide)?;
+                }
+            }
+            "transpose" => {
+                let amount = cmd.arg::<i32>(0, "number of semitones")?;
+                let columns = columns.filter(|col| col % TRACK_COLUMNS == 0);
+                self.change_rows(columns, (start, end), |selected| {
+                    block::transpose(selected, amount)
+                })?;
+            }
+            "fill" => {
+                let every = cmd.arg::<usize>(0, "step interval")?.max(1);
+                let value = match cmd.args.get(1) {
+                    Some(value) => Cell::parse(value, CellKind::of(self.col()))?,
+                    None => self.get_grid()[self.col()][self.row()],
+                };
+                if value.is_empty() {
+                    anyhow::bail!("nothing to fill with");
+                }
+                self.change_rows(self.col()..self.col() + 1, (start, end), |selected| {
+                    block::fill(selected, every, value)
+                })?;
+            }
+            "engine" => {
+                let (track, engine) = match cmd.args.len() {
+                    1 => (self.col() / TRACK_COLUMNS, cmd.arg::<usize>(0, "engine")?),
+                    _ => (self.track_arg(&cmd, 0)?, cmd.arg::<usize>(1, "engine")?),
+                };
+                if track < DRUM_TRACK_COUNT {
+                    anyhow::bail!("track {} is a drum track", track + 1);
+                }
+                if engine >= ENGINE_COUNT {
+                    anyhow::bail!("engine must be between 0 and {}", ENGINE_COUNT - 1);
+                }
+                self.engines[track] = engine;
+                self.control.0.send(Control::Engine { track, engine })?;
+            }
+            "d" | "y" => {
+                let register = match cmd.args.first() {
+                    Some(name) => match name.chars().next() {
+                        Some(name) if Registers::is_valid(name) => Some(name),
+                        _ => anyhow::bail!("invalid register: {}", name),
+                    },
+                    None => None,
+                };
+                let action = if cmd.name == "d" {
+                    Action::Delete
+                } else {
+                    Action::Yank
+                };
+                let cmd = KeyCommand {
+                    count: None,
+                    register,
+                    action,
+                };
+                let (start, end) = ((columns.start, line_start), (columns.end - 1, line_end));
+                self.change_block(cmd, start, end);
+            }
+            "s" | "substitute" => {
+                let sub = Substitute::parse(&cmd.args.join(" "))?;
+                let grid = self.get_grid();
+                let mut cmds = vec![];
+                let mut replaced_row = None;
+                for row in line_start..=line_end {
+                    for (col, column) in grid
+                        .iter()
+                        .enumerate()
+                        .take(columns.end)
+                        .skip(columns.start)
+                    {
+                        // without the g flag only the first match on a step is replaced
+                        if !sub.global && replaced_row == Some(row) {
+                            continue;
                         }
-                        'p' => {
-                            if let Some(reg) = &self.register {
-                                let cmd = Command::Insert {
-                                    x: self.x / CELL_WIDTH,
-                                    y: self.y,
-                                    input: reg.clone(),
-                                };
-                                self.apply(cmd);
-                            }
+                        if column[row].is_empty() {
+                            continue;
                         }
-                        '+' => {
-                            let value = &self.get_grid()[self.x / CELL_WIDTH][self.y - 1];
-                            if let Ok(value) = value.parse::<i32>() {
-                                let cmd = Command::Insert {
-                                    x: self.x / CELL_WIDTH,
-                                    y: self.y,
-                                    input: (value + 1).to_string(),
-                                };
-                                self.apply(cmd);
-                            } else if let Some(index) = PITCHES
-                                .iter()
-                                .position(|&p| p.to_uppercase() == value.to_uppercase())
-                            {
-                                let cmd = Command::Insert {
-                                    x: self.x / CELL_WIDTH,
-                                    y: self.y,
-                                    input: PITCHES[(index + 1) % PITCHES.len()].to_string(),
-                                };
-                                self.apply(cmd);
-                            }
+                        if let Some(input) = sub.replace(&column[row].to_string()) {
+                            // the replacement has to be valid for the column
+                            let cell = Cell::parse(&input, CellKind::of(col))
+                                .with_context(|| format!("step {}", row + 1))?;
+                            cmds.push(match cell {
+                                Cell::Empty => Command::Delete { x: col, y: row },
+                                cell => Command::Insert {
+                                    x: col,
+                                    y: row,
+                                    cell,
+                                },
+                            });
+                            replaced_row = Some(row);
                         }
-                        '-' => {
-                            let value = &self.get_grid()[self.x / CELL_WIDTH][self.y - 1];
-                            if let Ok(value) = value.parse::<i32>() {
-                                let cmd = Command::Insert {
-                                    x: self.x / CELL_WIDTH,
-                                    y: self.y,
-                                    input: (value - 1).to_string(),
-                                };
-                                self.apply(cmd);
-                            } else if let Some(index) = PITCHES
-                                .iter()
-                                .position(|&p| p.to_uppercase() == value.to_uppercase())
-                            {
-                                let index = if index == 0 {
-                                    PITCHES.len() - 1
-                                } else {
-                                    index - 1
-                                };
-                                let cmd = Command::Insert {
-                                    x: self.x / CELL_WIDTH,
-                                    y: self.y,
-                                    input: PITCHES[index].to_string(),
-                                };
-                                self.apply(cmd);
-                            }
+                    }
+                }
+                if cmds.is_empty() {
+                    anyhow::bail!("pattern not found: {}", sub.pattern);
+                }
+                self.messages = vec![format!("{} substitutions", cmds.len())];
+                self.last_search = Some(sub.pattern);
+                self.apply_all(cmds);
+            }
+            "reg" | "registers" => self.messages = self.registers.list(),
+            "map" | "nmap" | "vmap" | "imap" => {
+                let modes = match cmd.name.as_str() {
+                    "map" => "nv",
+                    name => &name[..1],
+                };
+                match cmd.args.as_slice() {
+                    [] => self.messages = self.mappings(modes),
+                    [keys, action] => {
+                        for mode in modes.chars() {
+                            self.map(mode, keys, action)?;
                         }
-                        _ => {}
                     }
+                    _ => anyhow::bail!("usage: :{} keys action", cmd.name),
                 }
-                (EditingMode::Insert, KeyCode::Char(ch)) => {
-                    self.curr_input.push(ch);
-
-                    self.update_selected_cell();
-                    self.x += 1;
+            }
+            "rec" => {
+                if self.capture.is_some() {
+                    self.stop_capture();
+                } else {
+                    let (_, config) = self
+                        .output
+                        .as_ref()
+                        .ok_or_else(|| anyhow::anyhow!("no audio to record"))?;
+                    let path = match cmd.args.first() {
+                        Some(path) => PathBuf::from(path),
+                        None => capture_path(),
+                    };
+                    // the file gets every channel the device plays, on one
+                    // with more than two that's each output pair rather than
+                    // a stereo mix
+                    let (recorder, tap) = Recorder::start(&path, config)?;
+                    let _ = self.taps.0.send(Some(tap));
+                    self.messages = vec![match config.channels {
+                        0..=2 => format!("recording to \"{}\"", path.display()),
+                        channels => format!(
+                            "recording all {} channels to \"{}\"",
+                            channels,
+                            path.display()
+                        ),
+                    }];
+                    self.capture = Some(recorder);
                 }
-                (EditingMode::Insert, KeyCode::Esc) => {
-                    self.mode = EditingMode::Normal;
+            }
+            "export" => {
+                if self.exporting.is_some() {
+                    anyhow::bail!("an export is already running");
+                }
+                let dir = cmd.arg::<PathBuf>(0, "directory")?;
+                let fader = match cmd.args.get(1) {
+                    Some(fader) => Fader::from_name(fader)?,
+                    None => Fader::Post,
+                };
+                let loops = match cmd.args.get(2) {
+                    Some(_) => cmd.arg::<usize>(2, "number of loops")?.max(1),
+                    None => 1,
+                };
+                // rendered at the rate the device plays at, or the one the
+                // voices are tuned for
+                let sample_rate = match &self.output {
+                    Some((_, config)) => config.sample_rate.0,
+                    None => EXPORT_SAMPLE_RATE,
+                };
+                let engine = self.engine(sample_rate);
+                let max_loops = Stems::max_loops(&engine, sample_rate);
+                if loops > max_loops {
+                    anyhow::bail!(
+                        "at most {} loops fit in {} minutes",
+                        max_loops,
+                        export::MAX_LENGTH / 60
+                    );
+                }
+                // editing goes on while it renders, the engine has its own
+                // copy of the pattern
+                let (done, result) = crossbeam::channel::bounded(1);
+                let target = dir.clone();
+                thread::spawn(move || {
+                    let _ = done.send(Stems::render(engine, sample_rate, loops, fader, &target));
+                });
+                self.messages = vec![format!("exporting to \"{}\"", dir.display())];
+                self.exporting = Some((dir, result));
+            }
+            "devices" => {
+                let current = self.output.as_ref().map(|(name, _)| name);
+                self.messages = audio::device_names()?
+                    .into_iter()
+                    .map(|name| match current {
+                        Some(current) if *current == name => format!("* {}", name),
+                        _ => format!("  {}", name),
+                    })
+                    .collect();
+                if let Some((name, config)) = &self.output {
+                    let buffer = match config.buffer_size {
+                        cpal::BufferSize::Fixed(frames) => format!("{} frames", frames),
+                        cpal::BufferSize::Default => "default buffer".to_string(),
+                    };
+                    self.messages.push(format!(
+                        "{}: {} Hz, {} channels, {}",
+                        name, config.sample_rate.0, config.channels, buffer
+                    ));
                 }
-                (EditingMode::Command, KeyCode::Enter) => {
-                    if self.cmd_line.trim() == ":q" {
-                        self.exit = true
+            }
+            "route" => {
+                if cmd.args.is_empty() {
+                    self.messages = (0..SEQ_TRACK_COUNT)
+                        .map(|track| {
+                            format!(
+                                "{:<12} {}",
+                                self.track_name(track),
+                                pair_name(self.routes[track])
+                            )
+                        })
+                        .collect();
+                } else {
+                    let track = self.track_arg(&cmd, 0)?;
+                    let output = cmd.arg::<usize>(1, "output pair")?;
+                    // pairs the device doesn't have are refused here but kept
+                    // from the config, another device might have them
+                    let pairs = match &self.output {
+                        Some((_, config)) => (config.channels as usize / 2).max(1),
+                        None => SEQ_TRACK_COUNT,
+                    };
+                    if !(1..=pairs.min(SEQ_TRACK_COUNT)).contains(&output) {
+                        anyhow::bail!(
+                            "output pair must be between 1 and {}",
+                            pairs.min(SEQ_TRACK_COUNT)
+                        );
                     }
-                    self.cmd_line = String::from("");
-                    self.mode = EditingMode::Normal;
+                    self.route(track, output - 1)?;
                 }
-                (EditingMode::Command, KeyCode::Char(c)) => {
-                    self.cmd_line.push(c);
+            }
+            "source" => {
+                let path = cmd.args.first().map(PathBuf::from).or_else(Config::path);
+                self.source(path.as_deref())?;
+                if let Some(path) = path {
+                    self.messages = vec![format!("\"{}\" sourced", path.display())];
                 }
-                (EditingMode::Command, KeyCode::Backspace) => {
-                    self.cmd_line.pop();
+            }
+            "earlier" | "later" => {
+                let steps = Steps::parse(cmd.args.first().map(|arg| arg.as_str()))?;
+                match (cmd.name.as_str(), steps) {
+                    ("earlier", Steps::Count(count)) => self.history.earlier(count),
+                    ("earlier", Steps::Time(duration)) => self.history.earlier_by(duration),
+                    (_, Steps::Count(count)) => self.history.later(count),
+                    (_, Steps::Time(duration)) => self.history.later_by(duration),
                 }
-                (EditingMode::Visual, KeyCode::Esc) => {
-                    self.mode = EditingMode::Normal;
+                self.clamp_cursor();
+                self.revision_message();
+            }
+            name => anyhow::bail!("not an editor command: {}", name),
+        }
+        Ok(())
+    }
+
+    pub fn set_option(&mut self, name: &str, value: &str) -> anyhow::Result<()> {
+        match name {
+            "bpm" => {
+                let bpm = value
+                    .parse::<f32>()
+                    .map_err(|_| anyhow::anyhow!("invalid bpm: {}", value))?;
+                self.set_bpm(check_bpm(bpm)?)?;
+            }
+            "step" => match value.parse::<usize>() {
+                Ok(step) => self.edit_step = check_edit_step(step)?,
+                _ => anyhow::bail!("step must be between 0 and {}", MAX_EDIT_STEP),
+            },
+            "octave" => match value.parse::<i32>() {
+                Ok(octave) if (0..=MAX_OCTAVE).contains(&octave) => self.octave = octave,
+                _ => anyhow::bail!("octave must be between 0 and {}", MAX_OCTAVE),
+            },
+            // audio settings are used when the stream is opened
+            "audio" => self.audio.backend = Backend::from_name(value)?,
+            "device" => {
+                self.audio.device = match value {
+                    "default" => None,
+                    name => Some(name.to_string()),
                 }
-                (_, _) => {}
+            }
+            "samplerate" => match value.parse::<u32>() {
+                Ok(rate) if rate > 0 => self.audio.sample_rate = Some(rate),
+                _ => anyhow::bail!("invalid sample rate: {}", value),
+            },
+            "transport" => match value {
+                "on" => self.audio.transport = true,
+                "off" => self.audio.transport = false,
+                _ => anyhow::bail!("transport is either on or off: {}", value),
+            },
+            "buffersize" => match value.parse::<u32>() {
+                Ok(frames) if frames > 0 => self.audio.buffer_size = Some(frames),
+                _ => anyhow::bail!("invalid buffer size: {}", value),
             },
-            _ => (),
+            _ => anyhow::bail!("unknown option: {}", name),
         }
+        Ok(())
     }
 
-    fn update_selected_cell(&mut self) {
-        let input = self.curr_input.clone().into_iter().collect::<String>();
-        let cmd = Command::Insert {
-            x: self.x / CELL_WIDTH,
-            y: self.y,
-            input,
+    fn set_bpm(&mut self, bpm: f32) -> anyhow::Result<()> {
+        self.bpm = bpm;
+        self.control.0.send(Control::Bpm(bpm))?;
+        Ok(())
+    }
+
+    // read the config file at startup, without one the defaults are used
+    pub fn load_config(&mut self) {
+        let path = Config::path().filter(|path| path.exists());
+        if let Err(err) = self.source(path.as_deref()) {
+            self.error = Some(format!("{:#}", err));
+        }
+    }
+
+    fn source(&mut self, path: Option<&Path>) -> anyhow::Result<()> {
+        let config = match path {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
         };
+        // everything is checked before anything changes, so a config with a
+        // mistake in it leaves the current settings as they were
+        let bpm = check_bpm(config.bpm)?;
+        let edit_step = check_edit_step(config.edit_step)?;
+        // mappings start over from the defaults so removed lines take effect
+        let mut keys = KeyParser::new();
+        let mut piano = PIANO_KEYS.to_vec();
+        for (mode, keymap) in [
+            ('n', &config.map.normal),
+            ('v', &config.map.visual),
+            ('i', &config.map.insert),
+        ] {
+            for (seq, action) in keymap {
+                map(&mut keys, &mut piano, mode, seq, action)?;
+            }
+        }
 
-        self.apply(cmd);
+        // the defaults only apply to a new pattern, a loaded project keeps its
+        // own tempo and engines
+        if self.path.is_none() {
+            self.set_bpm(bpm)?;
+            for (track, &engine) in config.engines.iter().enumerate().skip(DRUM_TRACK_COUNT) {
+                self.engines[track] = engine;
+                self.control.0.send(Control::Engine { track, engine })?;
+            }
+        }
+        self.edit_step = edit_step;
+        for (track, &output) in config.routes.iter().enumerate() {
+            self.route(track, output - 1)?;
+        }
+        self.keys = keys;
+        self.piano = piano;
+        self.colors = config.colors;
+        self.remaps = config.keys;
+        self.audio = config.audio;
+        Ok(())
     }
 
-    fn yank(&mut self) {
-        self.register = Some(self.get_grid()[self.x / CELL_WIDTH][self.y - 1].clone());
+    fn map(&mut self, mode: char, keys: &str, action: &str) -> anyhow::Result<()> {
+        map(&mut self.keys, &mut self.piano, mode, keys, action)
     }
 
-    fn apply(&mut self, cmd: Command) {
-        let mut state = self.get_grid().clone();
-        match cmd {
-            Command::Insert { x, y, input } => state[x][y - 1] = input,
-            Command::Delete { x, y } => state[x][y - 1] = "___ ".to_string(),
+    fn mappings(&self, modes: &str) -> Vec<String> {
+        let mut lines = vec![];
+        for mode in modes.chars() {
+            if mode == 'i' {
+                lines.extend(
+                    self.piano
+                        .iter()
+                        .filter(|key| !PIANO_KEYS.contains(key))
+                        .map(|(key, semitone)| format!("i  {}  note{}", key, semitone)),
+                );
+                continue;
+            }
+            lines.extend(
+                self.keys
+                    .mappings(mode == 'v')
+                    .iter()
+                    .map(|(keys, action)| format!("{}  {}  {}", mode, keys, action.name())),
+            );
+        }
+        if lines.is_empty() {
+            lines.push("no mappings".to_string());
         }
+        lines
+    }
 
-        self.history.push(state);
+    fn revision_message(&mut self) {
+        let (revision, time) = self.history.revision();
+        let age = SystemTime::now()
+            .duration_since(time)
+            .unwrap_or_default()
+            .as_secs();
+        self.messages = vec![format!("revision {}, {}s ago", revision, age)];
     }
 
-    pub fn run(&mut self) -> anyhow::Result<()> {
-        let mut engine = Engine::new();
-        engine.init();
+    fn set_glide(&mut self, track: usize, glide: [bool; PARAM_COUNT]) -> anyhow::Result<()> {
+        self.glide[track] = glide;
+        for (&param, &on) in PARAMS.iter().zip(&glide) {
+            self.control.0.send(Control::Glide { track, param, on })?;
+        }
+        Ok(())
+    }
 
-        let host = cpal::default_host();
-        let device = host
-            .default_output_device()
-            .expect("Failed to get default output device");
-        let config = device.default_output_config().unwrap();
+    fn route(&mut self, track: usize, output: usize) -> anyhow::Result<()> {
+        self.routes[track] = output;
+        self.control.0.send(Control::Route { track, output })?;
+        Ok(())
+    }
 
-        let channels = config.channels() as usize;
+    fn track_arg(&self, cmd: &ExCommand, idx: usize) -> anyhow::Result<usize> {
+        // tracks are numbered from 1 on the command line
+        match cmd.arg::<usize>(idx, "track")? {
+            track @ 1..=SEQ_TRACK_COUNT => Ok(track - 1),
+            track => anyhow::bail!("no such track: {}", track),
+        }
+    }
 
-        let (_, rx) = &self.history.channel;
-        let rx = rx.clone();
+    fn write(&mut self, path: Option<&String>) -> anyhow::Result<()> {
+        let path = match path {
+            Some(path) => PathBuf::from(path),
+            None => self
+                .path
+                .clone()
+                .ok_or_else(|| anyhow::anyhow!("no file name"))?,
+        };
+        let project = self.project();
+        project.save(&path)?;
+        self.saved = Some(project);
+        self.history.save(&undo_path(&path))?;
+        self.messages = vec![format!("\"{}\" written", path.display())];
+        self.path = Some(path);
+        Ok(())
+    }
 
-        let (_, ui_rx) = &engine.ui_channel;
-        let ui_rx = ui_rx.clone();
+    fn project(&self) -> Project {
+        Project {
+            bpm: self.bpm,
+            engines: self.engines,
+            glide: self.glide,
+            grid: self.get_grid().clone(),
+        }
+    }
+
+    // a pattern that was never written only counts as changed once something
+    // is in it, the options set by the config don't
+    fn check_saved(&self, bang: bool) -> anyhow::Result<()> {
+        let modified = match &self.saved {
+            Some(saved) => *saved != self.project(),
+            None => self.get_grid() != History::new().get_grid(),
+        };
+        if modified && !bang {
+            anyhow::bail!("no write since last change (add ! to override)");
+        }
+        Ok(())
+    }
 
-        let err_fn = |err| eprintln!("an error occurred on stream: {}", err);
+    fn edit(&mut self, path: Option<&String>) -> anyhow::Result<()> {
+        let path = match path {
+            Some(path) => PathBuf::from(path),
+            None => self
+                .path
+                .clone()
+                .ok_or_else(|| anyhow::anyhow!("no file name"))?,
+        };
+        let project = Project::load(&path)?;
 
-        let stream = device.build_output_stream(
-            &config.into(),
-            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                // update state if needed
-                if let Ok(grid) = rx.try_recv() {
-                    engine.set_state(grid);
+        self.history.reset(project.grid);
+        let undo = undo_path(&path);
+        if undo.exists() {
+            // a stale undo file shouldn't keep the project from loading
+            if let Err(err) = self.history.load(&undo) {
+                self.messages.push(format!("{:#}", err));
+            }
+        }
+        self.bpm = project.bpm;
+        self.control.0.send(Control::Bpm(project.bpm))?;
+        for (track, &engine) in project.engines.iter().enumerate() {
+            self.control.0.send(Control::Engine { track, engine })?;
+        }
+        self.engines = project.engines;
+        for (track, glide) in project.glide.iter().enumerate() {
+            self.set_glide(track, *glide)?;
+        }
+        self.clamp_cursor();
+        self.saved = Some(self.project());
+        self.messages
+            .insert(0, format!("\"{}\" loaded", path.display()));
+        self.path = Some(path);
+        Ok(())
+    }
+
+    fn set_length(&mut self, len: usize) {
+        self.apply(Command::Resize { len });
+        self.clamp_cursor();
+    }
+
+    // the pattern may have become shorter under the cursor, the screen and
+    // the selections, e.g. after undoing a :len
+    fn clamp_cursor(&mut self) {
+        let last = self.get_grid()[0].len() - 1;
+        self.y = self.y.min(last + 1);
+        self.scroll.1 = self.scroll.1.min(last);
+        if let Some((x, y)) = self.selection {
+            self.selection = Some((x, y.min(last)));
+        }
+        if let Some(((x0, y0), (x1, y1))) = self.last_visual {
+            self.last_visual = Some(((x0, y0.min(last)), (x1, y1.min(last))));
+        }
+    }
+
+    fn change_rows(
+        &mut self,
+        columns: impl Iterator<Item = usize>,
+        (start, end): (usize, usize),
+        f: impl Fn(&Block) -> Block,
+    ) -> anyhow::Result<()> {
+        // transform each column separately but record a single history entry
+        let mut cmds = vec![];
+        for col in columns {
+            let selected = self.get_block((col, start), (col, end));
+            cmds.extend(self.block_commands(col, start, f(&selected))?);
+        }
+        self.apply_all(cmds);
+        Ok(())
+    }
+
+    fn update_selected_cell(&mut self) {
+        let input = self.curr_input.drain(..).collect::<String>();
+        match Cell::parse(&input, CellKind::of(self.col())) {
+            Ok(cell) => {
+                let cmd = Command::Insert {
+                    x: self.col(),
+                    y: self.row(),
+                    cell,
+                };
+                self.apply(cmd);
+            }
+            Err(err) => self.error = Some(err.to_string()),
+        }
+    }
+
+    fn selection_bounds(&self) -> Option<((usize, usize), (usize, usize))> {
+        // top left and bottom right corners of the selected block, inclusive
+        let (x, y) = self.selection?;
+        Some((
+            (x.min(self.col()), y.min(self.row())),
+            (x.max(self.col()), y.max(self.row())),
+        ))
+    }
+
+    fn is_selected(&self, x: usize, y: usize) -> bool {
+        match self.selection_bounds() {
+            Some(((x0, y0), (x1, y1))) => (x0..=x1).contains(&x) && (y0..=y1).contains(&y),
+            None => false,
+        }
+    }
+
+    fn execute(&mut self, cmd: KeyCommand) {
+        let count = cmd.count.unwrap_or(1);
+        match cmd.action {
+            Action::Move(motion) => self.move_cursor(motion, cmd.count),
+            Action::Undo => {
+                (0..count).for_each(|_| self.history.undo());
+                self.clamp_cursor();
+            }
+            Action::Redo => {
+                (0..count).for_each(|_| self.history.redo());
+                self.clamp_cursor();
+            }
+            Action::Earlier => {
+                self.history.earlier(count);
+                self.clamp_cursor();
+                self.revision_message();
+            }
+            Action::Later => {
+                self.history.later(count);
+                self.clamp_cursor();
+                self.revision_message();
+            }
+            Action::Insert => {
+                // everything typed in one insert session is undone at once
+                self.history.begin();
+                self.mode = EditingMode::Insert;
+            }
+            Action::Visual => {
+                self.selection = Some((self.col(), self.row()));
+                self.mode = EditingMode::Visual;
+            }
+            Action::Command => {
+                // like vim, commands from visual mode act on the selected block
+                self.last_visual = self.selection_bounds().or(self.last_visual);
+                self.cmd_line = match self.selection.take() {
+                    Some(_) => ":'<,'>".to_string(),
+                    None => ":".to_string(),
+                };
+                self.mode = EditingMode::Command;
+            }
+            Action::Search => {
+                self.cmd_line = "/".to_string();
+                self.mode = EditingMode::Command;
+            }
+            Action::Repeat => self.repeat(cmd.count),
+            Action::Record(name) => self.recording = Some((name, vec![])),
+            Action::Play(name) => self.play_macro(name, count),
+            Action::TweakUp => self.tweak(count as i32),
+            Action::TweakDown => self.tweak(-(count as i32)),
+            _ => match self.selection_bounds() {
+                Some((start, end)) => {
+                    self.change_block(cmd, start, end);
+                    if cmd.action != Action::Yank {
+                        let size = (end.0 - start.0 + 1, end.1 - start.1 + 1);
+                        self.last_change = Some(Change::Block(cmd, size));
+                    }
+
+                    // like vim, leave the cursor at the start of the block
+                    self.x = start.0 * CELL_WIDTH;
+                    self.y = start.1 + 1;
+                    self.selection = None;
+                    self.mode = EditingMode::Normal;
                 }
-                for frame in data.chunks_mut(channels) {
-                    for sample in frame.iter_mut() {
-                        *sample = engine.tick();
+                None => {
+                    self.change(cmd);
+                    if cmd.action != Action::Yank {
+                        self.last_change = Some(Change::Normal(cmd));
                     }
                 }
             },
-            err_fn,
-            None,
-        )?;
-        stream.play()?;
+        }
+    }
+
+    // turns the parameter of the column under the cursor, with the cursor on
+    // the pitch column that's morph
+    fn tweak(&mut self, amount: i32) {
+        let (track, column) = (self.col() / TRACK_COLUMNS, self.col() % TRACK_COLUMNS);
+        let param = match column {
+            0 => Param::Morph,
+            1 => Param::Harmonics,
+            _ => Param::Timbre,
+        };
+        if track < DRUM_TRACK_COUNT && param == Param::Morph {
+            self.error = Some(format!("{} has no morph", self.track_name(track)));
+            return;
+        }
+        let value = &mut self.params[track][column];
+        *value = (*value as i32 + amount).clamp(0, MAX_VALUE as i32) as u8;
+        let value = *value;
+        let _ = self.control.0.send(Control::Param {
+            track,
+            param,
+            value: cell::scale(value),
+        });
+        self.messages = vec![format!(
+            "{} {} {:02}",
+            self.track_name(track),
+            param.name(),
+            value
+        )];
+        if self.record && self.stream.is_some() {
+            self.lock(track, param, value);
+        }
+    }
+
+    // writes a tweak into the step that is playing
+    fn lock(&mut self, track: usize, param: Param, value: u8) {
+        let step = self.active_step.max(0) as usize;
+        match lock_cell(self.get_grid(), track, step, param, value) {
+            Ok(Some((x, cell))) if self.get_grid()[x][step] != cell => {
+                self.apply(Command::Insert { x, y: step, cell })
+            }
+            Ok(_) => {}
+            Err(err) => self.error = Some(err.to_string()),
+        }
+    }
+
+    fn play_macro(&mut self, name: char, count: usize) {
+        let name = if name == '@' {
+            match self.last_macro {
+                Some(name) => name,
+                None => return,
+            }
+        } else {
+            name
+        };
+        let Some(events) = self.macros.get(&name).cloned() else {
+            return;
+        };
+        if self.macro_depth == 0 {
+            self.macro_keys = 0;
+            self.macro_abort = false;
+        }
+        // guard against macros that (indirectly) call themselves, stopping
+        // every macro that is playing rather than just this one
+        if self.macro_depth >= MAX_MACRO_DEPTH {
+            self.messages = vec![format!("macro @{} nested too deeply", name)];
+            self.macro_abort = true;
+            return;
+        }
+
+        self.last_macro = Some(name);
+        self.macro_depth += 1;
+        self.history.begin();
+        'replay: for _ in 0..count {
+            for event in &events {
+                if self.macro_abort {
+                    break 'replay;
+                }
+                if self.macro_keys >= MAX_MACRO_KEYS {
+                    self.messages = vec![format!("macro @{} played too many keys", name)];
+                    self.macro_abort = true;
+                    break 'replay;
+                }
+                self.macro_keys += 1;
+                self.process_key(event.clone());
+            }
+        }
+        self.history.commit();
+        self.macro_depth -= 1;
+    }
+
+    fn move_cursor(&mut self, motion: Motion, count: Option<usize>) {
+        let n = count.unwrap_or(1);
+        let (col, row) = (self.col(), self.row());
+        let cols = self.get_grid().len();
+        let rows = self.get_grid()[col].len();
+        let (col, row) = match motion {
+            Motion::Left => ((col + cols - n % cols) % cols, row),
+            Motion::Right => ((col + n) % cols, row),
+            Motion::Up => (col, (row + rows - n % rows) % rows),
+            Motion::Down => (col, (row + n) % rows),
+            Motion::FirstRow => (col, count.map_or(0, |n| n - 1).min(rows - 1)),
+            Motion::LastRow => (col, count.map_or(rows - 1, |n| n - 1).min(rows - 1)),
+            Motion::FirstColumn => (0, row),
+            Motion::LastColumn => (cols - 1, row),
+            Motion::NextTrack => {
+                let track = (col / TRACK_COLUMNS + n).min(cols / TRACK_COLUMNS - 1);
+                (track * TRACK_COLUMNS, row)
+            }
+            Motion::PrevTrack => {
+                let track = (col / TRACK_COLUMNS).saturating_sub(n);
+                (track * TRACK_COLUMNS, row)
+            }
+            Motion::NextBeat => (col, ((row / BEAT_LENGTH + n) * BEAT_LENGTH).min(rows - 1)),
+            Motion::PrevBeat => {
+                let beat = row.div_ceil(BEAT_LENGTH).saturating_sub(n);
+                (col, beat * BEAT_LENGTH)
+            }
+            Motion::NextMatch | Motion::PrevMatch => {
+                let forward = motion == Motion::NextMatch;
+                let mut found = (col, row);
+                for _ in 0..n {
+                    match self.find_match(found, forward) {
+                        Ok(pos) => found = pos,
+                        Err(err) => {
+                            self.error = Some(err.to_string());
+                            return;
+                        }
+                    }
+                }
+                found
+            }
+        };
+        self.x = col * CELL_WIDTH;
+        self.y = row + 1;
+    }
+
+    fn change(&mut self, cmd: KeyCommand) {
+        // normal mode changes act on the cell under the cursor, yank and
+        // delete extend downwards by the count
+        let (col, row) = (self.col(), self.row());
+        match cmd.action {
+            Action::Yank | Action::Delete => {
+                let rows = self.get_grid()[col].len();
+                let end = (row + cmd.count.unwrap_or(1) - 1).min(rows - 1);
+                self.change_block(cmd, (col, row), (col, end));
+            }
+            _ => self.change_block(cmd, (col, row), (col, row)),
+        }
+    }
+
+    fn repeat(&mut self, count: Option<usize>) {
+        match self.last_change {
+            Some(Change::Normal(mut cmd)) => {
+                cmd.count = count.or(cmd.count);
+                self.change(cmd);
+            }
+            Some(Change::Block(mut cmd, (width, height))) => {
+                cmd.count = count.or(cmd.count);
+                let (col, row) = (self.col(), self.row());
+                let grid = self.get_grid();
+                let end = (
+                    (col + width - 1).min(grid.len() - 1),
+                    (row + height - 1).min(grid[col].len() - 1),
+                );
+                self.change_block(cmd, (col, row), end);
+            }
+            None => {}
+        }
+    }
+
+    fn change_block(&mut self, cmd: KeyCommand, start: (usize, usize), end: (usize, usize)) {
+        let count = cmd.count.unwrap_or(1);
+        let selected = self.get_block(start, end);
+        let result = match cmd.action {
+            Action::Yank => {
+                self.set_register(cmd.register, selected);
+                None
+            }
+            Action::Delete => {
+                let cleared = block::clear(&selected);
+                self.set_register(cmd.register, selected);
+                Some(cleared)
+            }
+            Action::Paste => self
+                .registers
+                .get(cmd.register)
+                // more copies than steps would all be cut off anyway
+                .map(|reg| block::repeat(reg, count.min(MAX_STEP_COUNT))),
+            Action::Increment => Some(block::transpose(&selected, count as i32)),
+            Action::Decrement => Some(block::transpose(&selected, -(count as i32))),
+            Action::Interpolate => Some(block::interpolate(&selected)),
+            Action::Reverse => Some(block::reverse(&selected)),
+            Action::Randomize => Some(block::randomize(&selected)),
+            _ => None,
+        };
+        if let Some(result) = result {
+            self.put_block(start.0, start.1, result);
+        }
+    }
+
+    fn get_block(&self, start: (usize, usize), end: (usize, usize)) -> Block {
+        self.get_grid()[start.0..=end.0]
+            .iter()
+            .map(|column| column[start.1..=end.1].to_vec())
+            .collect()
+    }
+
+    fn put_block(&mut self, x: usize, y: usize, block: Block) {
+        match self.block_commands(x, y, block) {
+            Ok(cmds) => self.apply_all(cmds),
+            Err(err) => self.error = Some(err.to_string()),
+        }
+    }
+
+    fn block_commands(&self, x: usize, y: usize, block: Block) -> anyhow::Result<Vec<Command>> {
+        // paste with the top left corner at x, y, clipped to the grid
+        let grid = self.get_grid();
+        let mut cmds = vec![];
+        for (dx, column) in block.into_iter().enumerate() {
+            let Some(target) = grid.get(x + dx) else {
+                break;
+            };
+            for (dy, cell) in column.into_iter().enumerate() {
+                match target.get(y + dy) {
+                    // notes can't end up in parameter columns and vice versa
+                    Some(_) if !cell.fits(CellKind::of(x + dx)) => {
+                        anyhow::bail!("{} doesn't fit in column {}", cell, x + dx + 1)
+                    }
+                    Some(prev) if *prev != cell => cmds.push(Command::Insert {
+                        x: x + dx,
+                        y: y + dy,
+                        cell,
+                    }),
+                    Some(_) => {}
+                    None => break,
+                }
+            }
+        }
+        Ok(cmds)
+    }
 
-        self.draw_ui(ui_rx)?;
+    fn set_register(&mut self, name: Option<char>, block: Block) {
+        if let Err(err) = self.registers.set(name, block) {
+            self.messages = vec![format!("failed to copy to clipboard: {}", err)];
+        }
+    }
+
+    fn apply(&mut self, cmd: Command) {
+        self.apply_all(vec![cmd]);
+    }
+
+    fn apply_all(&mut self, cmds: Vec<Command>) {
+        // all commands end up in a single history entry
+        self.history.apply(cmds);
+    }
 
+    // reopen the stream when the settings changed, the device went away or
+    // another device became the default, returns whether anything changed
+    fn check_audio(&mut self) -> bool {
+        self.last_audio_check = Instant::now();
+        let changed = self.opened.as_ref() != Some(&self.audio);
+        if !changed && self.output.is_some() {
+            self.look_up_default_device();
+            return false;
+        }
+        self.reopen_stream(changed)
+    }
+
+    fn look_up_default_device(&mut self) {
+        // a device picked by name stays put, whatever the default is
+        let follows = self.audio.backend == Backend::Cpal && self.audio.device.is_none();
+        if !follows || self.default_device.is_some() {
+            return;
+        }
+        let (found, lookup) = crossbeam::channel::bounded(1);
+        thread::spawn(move || {
+            let _ = found.send(audio::default_device_name());
+        });
+        self.default_device = Some(lookup);
+    }
+
+    // moves to the new default device once a lookup finds one
+    fn check_default_device(&mut self) -> bool {
+        let Some(lookup) = &self.default_device else {
+            return false;
+        };
+        let default = match lookup.try_recv() {
+            Ok(default) => default,
+            Err(TryRecvError::Empty) => return false,
+            Err(TryRecvError::Disconnected) => None,
+        };
+        self.default_device = None;
+        // the settings may have changed while it looked
+        let moved = match (&self.output, default) {
+            (Some((name, _)), Some(default)) => {
+                self.audio.backend == Backend::Cpal
+                    && self.audio.device.is_none()
+                    && default != *name
+            }
+            _ => false,
+        };
+        moved && self.reopen_stream(false)
+    }
+
+    fn reopen_stream(&mut self, changed: bool) -> bool {
+        // looking for a lost device again doesn't repeat the error
+        let retry = !changed && self.output.is_none();
+        self.opened = Some(self.audio.clone());
+        match self.open_stream() {
+            Ok(()) => {
+                if let Some((name, _)) = &self.output {
+                    self.messages = vec![format!("audio: {}", name)];
+                }
+            }
+            Err(_) if retry => {}
+            Err(err) => self.error = Some(format!("no audio: {:#}", err)),
+        }
+        // audio backends like alsa print their errors straight to the terminal
+        self.screen.invalidate();
+        true
+    }
+
+    fn open_stream(&mut self) -> anyhow::Result<()> {
+        self.stream = None;
+        self.output = None;
+        self.stop_capture();
+        let (stream, name, config) = match self.audio.backend {
+            Backend::Null => {
+                let config = audio::null_config(&self.audio);
+                let stream = NullOutput::start(self.driver(&config), &config);
+                (Output::Null(stream), "null".to_string(), config)
+            }
+            Backend::Cpal => {
+                let (device, config) = audio::open_output(&self.audio)?;
+                let driver = self.driver(&config);
+                let stream =
+                    audio::start_stream(&device, &config, driver, self.stream_errors.0.clone())?;
+                (
+                    Output::Stream(stream),
+                    device.name().unwrap_or_default(),
+                    config,
+                )
+            }
+            #[cfg(feature = "jack")]
+            Backend::Jack => {
+                let (client, config) = JackOutput::connect()?;
+                let name = client.name().to_string();
+                let driver = self.driver(&config);
+                let errors = self.stream_errors.0.clone();
+                let stream = JackOutput::start(client, driver, self.audio.transport, errors)?;
+                (Output::Jack(stream), name, config)
+            }
+            #[cfg(not(feature = "jack"))]
+            Backend::Jack => anyhow::bail!("built without jack support"),
+        };
+        self.stream = Some(stream);
+        self.output = Some((name, config));
         Ok(())
     }
 
-    fn draw_ui(&mut self, rx: Receiver<i8>) -> anyhow::Result<()> {
+    // a fresh engine at the output's rate, picking up the pattern and settings
+    // where the last one left off
+    fn driver(&mut self, config: &StreamConfig) -> Driver {
+        let engine = self.engine(config.sample_rate.0);
+        self.steps = engine.ui_channel.1.clone();
+        Driver::new(
+            engine,
+            self.history.channel.1.clone(),
+            self.control.1.clone(),
+            self.taps.1.clone(),
+        )
+    }
+
+    // reports a finished :export, returns whether it did
+    fn check_export(&mut self) -> bool {
+        let Some((dir, result)) = &self.exporting else {
+            return false;
+        };
+        let result = match result.try_recv() {
+            Ok(result) => result,
+            Err(TryRecvError::Empty) => return false,
+            Err(TryRecvError::Disconnected) => Err(anyhow::anyhow!("the export thread panicked")),
+        };
+        match result {
+            Ok(stems) => {
+                self.messages = vec![format!(
+                    "{} files written to \"{}\", {:.1} s",
+                    stems.files().len(),
+                    dir.display(),
+                    stems.duration()
+                )]
+            }
+            Err(err) => self.error = Some(format!("{:#}", err)),
+        }
+        self.exporting = None;
+        true
+    }
+
+    fn stop_capture(&mut self) {
+        let Some(recorder) = self.capture.take() else {
+            return;
+        };
+        // a tap the audio thread didn't pick up yet would keep the writer
+        // waiting
+        while self.taps.1.try_recv().is_ok() {}
+        let _ = self.taps.0.send(None);
+        let path = recorder.path().display().to_string();
+        match recorder.finish() {
+            Ok((frames, 0)) => {
+                self.messages = vec![format!("\"{}\" recorded, {} frames", path, frames)]
+            }
+            Ok((frames, dropped)) => {
+                self.messages = vec![format!(
+                    "\"{}\" recorded, {} frames, {} dropped",
+                    path, frames, dropped
+                )]
+            }
+            Err(err) => self.error = Some(format!("{:#}", err)),
+        }
+    }
+
+    // an engine playing the pattern with the current tempo, engines and routes
+    fn engine(&self, sample_rate: u32) -> Engine<'static> {
+        let mut engine = Engine::new(sample_rate as f32);
+        engine.init();
+        engine.set_state(History::to_state(self.get_grid()));
+        engine.control(Control::Bpm(self.bpm));
+        for (track, &synth) in self.engines.iter().enumerate() {
+            engine.control(Control::Engine {
+                track,
+                engine: synth,
+            });
+        }
+        for (track, &output) in self.routes.iter().enumerate() {
+            engine.control(Control::Route { track, output });
+        }
+        for (track, glide) in self.glide.iter().enumerate() {
+            for (&param, &on) in PARAMS.iter().zip(glide) {
+                engine.control(Control::Glide { track, param, on });
+            }
+        }
+        for (track, values) in self.params.iter().enumerate() {
+            for (param, &value) in [Param::Morph, Param::Harmonics, Param::Timbre]
+                .iter()
+                .zip(values)
+            {
+                engine.control(Control::Param {
+                    track,
+                    param: *param,
+                    value: cell::scale(value),
+                });
+            }
+        }
+        engine
+    }
+
+    fn stream_error(&mut self, err: StreamError) {
+        if let StreamError::DeviceNotAvailable = err {
+            // check_audio opens it again once it's back, the recording
+            // doesn't carry over
+            self.stream = None;
+            self.output = None;
+            self.stop_capture();
+        }
+        self.error = Some(format!("audio: {}", err));
+    }
+
+    pub fn run(&mut self) -> anyhow::Result<()> {
         enable_raw_mode()?;
         let mut stdout = stdout();
         terminal::enable_raw_mode()?;
 
+        // only redraw when a key was pressed, the playhead moved or the audio
+        // device changed
+        let mut dirty = true;
         loop {
-            // TODO: redraw on every beat instead of continuously
-            rx.try_recv().map(|s| self.active_step = s).ok();
-            self.draw()?;
+            while let Ok(err) = self.stream_errors.1.try_recv() {
+                self.stream_error(err);
+                dirty = true;
+            }
+            if self.opened.as_ref() != Some(&self.audio)
+                || self.last_audio_check.elapsed() >= AUDIO_CHECK_INTERVAL
+            {
+                dirty |= self.check_audio();
+            }
+            dirty |= self.check_default_device();
+            // without a stream nothing reads the edits and settings meant for
+            // the engine, the next one starts from the current state anyway
+            if self.stream.is_none() {
+                while self.history.channel.1.try_recv().is_ok() {}
+                while self.control.1.try_recv().is_ok() {}
+            }
+            dirty |= self.check_export();
+            if let Some(step) = self.steps.try_iter().last() {
+                dirty |= step != self.active_step;
+                self.active_step = step;
+            }
+            if dirty {
+                self.draw()?;
+                dirty = false;
+            }
 
             if poll(Duration::from_millis(10))? {
                 let evt = read()?;
                 self.process_key(evt);
+                dirty = true;
                 if self.exit {
                     break;
                 }
             }
         }
 
+        // the file is only complete once the writer finalized it
+        self.stream = None;
+        self.stop_capture();
         disable_raw_mode()?;
         queue!(stdout, cursor::Show)?;
 
         Ok(())
     }
 }
+
+// bind keys in normal (n), visual (v) or insert (i) mode, insert mode keys
+// play notes named note0 to note24 in semitones above the octave's C
+fn map(
+    parser: &mut KeyParser,
+    piano: &mut Vec<(char, i32)>,
+    mode: char,
+    keys: &str,
+    action: &str,
+) -> anyhow::Result<()> {
+    if mode != 'i' {
+        return parser.map(keys, Action::from_name(action)?, mode == 'v');
+    }
+    let mut chars = keys.chars();
+    let (Some(key), None) = (chars.next(), chars.next()) else {
+        anyhow::bail!("insert mode maps single keys: {}", keys);
+    };
+    let semitone = action
+        .strip_prefix("note")
+        .and_then(|semitone| semitone.parse::<i32>().ok())
+        .filter(|semitone| (0..=MAX_PIANO_NOTE).contains(semitone))
+        .ok_or_else(|| anyhow::anyhow!("expected note0 to note{}: {}", MAX_PIANO_NOTE, action))?;
+    piano.retain(|&(bound, _)| bound != key);
+    piano.push((key, semitone));
+    Ok(())
+}
+
+fn check_bpm(bpm: f32) -> anyhow::Result<f32> {
+    if !(MIN_BPM..=MAX_BPM).contains(&bpm) {
+        anyhow::bail!("bpm must be between {} and {}", MIN_BPM, MAX_BPM);
+    }
+    Ok(bpm)
+}
+
+fn check_edit_step(step: usize) -> anyhow::Result<usize> {
+    if step > MAX_EDIT_STEP {
+        anyhow::bail!("step must be between 0 and {}", MAX_EDIT_STEP);
+    }
+    Ok(step)
+}
+
+// the undo tree is kept next to the project, e.g. song.bl8.undo
+fn undo_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".undo");
+    PathBuf::from(name)
+}
+
+// where a lock goes on a step: its column when that is empty or already holds
+// the same kind of value, morph taking whichever column does. steps without a
+// note are left alone as there is nothing for the value to apply to, and other
+// cells like velocities are never overwritten
+fn lock_cell(
+    grid: &Grid,
+    track: usize,
+    step: usize,
+    param: Param,
+    value: u8,
+) -> anyhow::Result<Option<(usize, Cell)>> {
+    let x = track * TRACK_COLUMNS;
+    if !matches!(grid[x].get(step), Some(Cell::Pitch(_))) {
+        return Ok(None);
+    }
+    let (columns, cell) = match param {
+        Param::Harmonics => (x + 1..x + 2, Cell::Param(value)),
+        Param::Timbre => (x + 2..x + 3, Cell::Param(value)),
+        Param::Morph => (x + 1..x + TRACK_COLUMNS, Cell::Fx(Fx::Morph(value))),
+    };
+    // a column already locking the parameter wins over an empty one
+    let fits = |x: &usize| grid[*x][step].same_kind(&cell);
+    match columns
+        .clone()
+        .find(fits)
+        .or_else(|| columns.clone().find(|&x| grid[x][step].is_empty()))
+    {
+        Some(x) => Ok(Some((x, cell))),
+        None => anyhow::bail!("step {} has no room for a {} lock", step + 1, param.name()),
+    }
+}
+
+// recordings without a name go to the current directory, e.g.
+// bl8-1760812345.wav
+fn capture_path() -> PathBuf {
+    let secs = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default();
+    PathBuf::from(format!("bl8-{}.wav", secs))
+}
+
+// output pairs are named after their channels, 1/2, 3/4 and so on
+fn pair_name(output: usize) -> String {
+    format!("{}/{}", output * 2 + 1, output * 2 + 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::INITIAL_STEP_COUNT;
+
+    #[test]
+    fn test_lock_cell() {
+        let mut grid = vec![vec![Cell::Empty; 4]; SEQ_TRACK_COUNT * TRACK_COLUMNS];
+        let x = 3 * TRACK_COLUMNS;
+        grid[x][..3].fill(Cell::Pitch(60));
+        grid[x + 1][1] = Cell::Velocity(80);
+        grid[x + 2][1] = Cell::Fx(Fx::Engine(4));
+        grid[x + 1][2] = Cell::Fx(Fx::Morph(10));
+        grid[x + 2][2] = Cell::Param(30);
+        let lock = |step, param| lock_cell(&grid, 3, step, param, 60);
+
+        // free columns and steps without a note
+        assert_eq!(
+            lock(0, Param::Harmonics).unwrap(),
+            Some((x + 1, Cell::Param(60)))
+        );
+        assert_eq!(
+            lock(0, Param::Morph).unwrap(),
+            Some((x + 1, Cell::Fx(Fx::Morph(60))))
+        );
+        assert_eq!(lock(3, Param::Timbre).unwrap(), None);
+
+        // velocities, engines and morphs stay where they are
+        assert!(lock(1, Param::Harmonics).is_err());
+        assert!(lock(1, Param::Timbre).is_err());
+        assert!(lock(1, Param::Morph).is_err());
+        assert!(lock(2, Param::Harmonics).is_err());
+
+        // a lock of the same kind is replaced, morph finds its own column
+        assert_eq!(
+            lock(2, Param::Timbre).unwrap(),
+            Some((x + 2, Cell::Param(60)))
+        );
+        assert_eq!(
+            lock(2, Param::Morph).unwrap(),
+            Some((x + 1, Cell::Fx(Fx::Morph(60))))
+        );
+    }
+
+    #[test]
+    fn test_source_checks_everything_first() {
+        let path = std::env::temp_dir().join(format!("bl8-config-{}.toml", std::process::id()));
+        let mut app = App::new();
+        let defaults = app.mappings("n");
+        // the last mapping is wrong, nothing before it may stick
+        std::fs::write(
+            &path,
+            "bpm = 140\nedit_step = 4\n[map.normal]\ngu = \"undo\"\n[map.insert]\nq = \"note99\"\n",
+        )
+        .unwrap();
+        assert!(app.source(Some(&path)).is_err());
+        assert_eq!(app.bpm, DEFAULT_BPM);
+        assert_eq!(app.edit_step, 1);
+        assert_eq!(app.mappings("n"), defaults);
+
+        std::fs::write(
+            &path,
+            "bpm = 140\nedit_step = 4\n[map.normal]\ngu = \"undo\"\n",
+        )
+        .unwrap();
+        app.source(Some(&path)).unwrap();
+        assert_eq!(app.bpm, 140.0);
+        assert_eq!(app.edit_step, 4);
+        assert_ne!(app.mappings("n"), defaults);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // a carriage return stands for enter and \x1b for escape
+    fn keys(keys: &str) -> Vec<Event> {
+        keys.chars()
+            .map(|ch| match ch {
+                '\r' => KeyCode::Enter,
+                '\x1b' => KeyCode::Esc,
+                ch => KeyCode::Char(ch),
+            })
+            .map(|code| Event::Key(KeyEvent::from(code)))
+            .collect()
+    }
+
+    fn type_keys(app: &mut App, input: &str) {
+        for key in keys(input) {
+            app.process_key(key);
+        }
+    }
+
+    #[test]
+    fn test_undo_keeps_cursor_in_pattern() {
+        let mut app = App::new();
+        type_keys(&mut app, ":len 32\r32Gvk:\x1b");
+        type_keys(&mut app, "u");
+        assert_eq!(app.get_grid()[0].len(), INITIAL_STEP_COUNT);
+        assert_eq!(app.row(), INITIAL_STEP_COUNT - 1);
+        // deleting at the cursor and over the last selection used to slice
+        // past the end of the pattern
+        type_keys(&mut app, "x:'<,'>d\r");
+        assert_eq!(app.error, None);
+        type_keys(&mut app, ":later\rx");
+        assert_eq!(app.get_grid()[0].len(), 32);
+    }
+
+    #[test]
+    fn test_insert_octave() {
+        let mut app = App::new();
+        type_keys(&mut app, "i5");
+        assert_eq!(app.octave, 5);
+        type_keys(&mut app, "9");
+        assert_eq!(app.octave, 5);
+        assert!(app.error.is_some());
+    }
+
+    #[test]
+    fn test_quit_with_changes() {
+        let mut app = App::new();
+        type_keys(&mut app, ":q\r");
+        assert!(app.exit);
+
+        let mut app = App::new();
+        type_keys(&mut app, ":len 32\r:q\r");
+        assert!(!app.exit);
+        assert!(app.error.is_some());
+        let path = std::env::temp_dir().join(format!("bl8-quit-{}.bl8", std::process::id()));
+        type_keys(&mut app, &format!(":w {}\r:len 16\r:e\r", path.display()));
+        assert_eq!(app.get_grid()[0].len(), 16);
+        type_keys(&mut app, ":e!\r");
+        assert_eq!(app.get_grid()[0].len(), 32);
+        type_keys(&mut app, ":q\r");
+        assert!(app.exit);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(undo_path(&path)).unwrap();
+
+        let mut app = App::new();
+        type_keys(&mut app, ":len 32\r:q!\r");
+        assert!(app.exit);
+    }
+
+    #[test]
+    fn test_runaway_macros() {
+        let mut app = App::new();
+        // each level plays itself twice, the first error has to stop them all
+        app.macros.insert('a', keys("2@a"));
+        app.play_macro('a', 1);
+        assert!(app.macro_keys <= 3 * MAX_MACRO_DEPTH);
+        assert_eq!(app.macro_depth, 0);
+
+        // no recursion, but far too many keys
+        app.macros.insert('b', keys("j"));
+        app.macros.insert('c', keys("999@b"));
+        app.macros.insert('d', keys("999@c"));
+        app.play_macro('d', 1);
+        assert_eq!(app.macro_keys, MAX_MACRO_KEYS);
+        assert_eq!(app.macro_depth, 0);
+    }
+}
@@ -0,0 +1,260 @@
+use anyhow::{anyhow, bail, Context, Result};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{
+    BufferSize, Device, SampleRate, Stream, StreamConfig, StreamError, SupportedBufferSize,
+};
+use crossbeam::channel::*;
+use serde::Deserialize;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::config::AudioConfig;
+use crate::engine::{Control, Engine, State, SEQ_TRACK_COUNT};
+#[cfg(feature = "jack")]
+use crate::jack_output::JackOutput;
+use crate::recorder::Tap;
+
+// what the null backend runs at unless told otherwise
+const NULL_SAMPLE_RATE: u32 = 48000;
+const NULL_BUFFER_SIZE: u32 = 512;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    // the system's audio through cpal
+    #[default]
+    Cpal,
+    // no sound card at all, the engine runs and its output is dropped
+    Null,
+    // a jack client with a port per track, when built with the jack feature
+    Jack,
+}
+
+impl Backend {
+    pub fn from_name(name: &str) -> Result<Backend> {
+        match name {
+            "cpal" => Ok(Backend::Cpal),
+            "null" => Ok(Backend::Null),
+            "jack" => Ok(Backend::Jack),
+            _ => bail!("unknown audio backend: {}", name),
+        }
+    }
+}
+
+// the engine and the channels feeding it from the ui, run by whichever
+// backend produces the audio
+pub struct Driver {
+    pub engine: Engine<'static>,
+    states: Receiver<State>,
+    controls: Receiver<Control>,
+    // a recording is started by sending a tap and stopped by sending none
+    taps: Receiver<Option<Tap>>,
+    tap: Option<Tap>,
+}
+
+impl Driver {
+    pub fn new(
+        engine: Engine<'static>,
+        states: Receiver<State>,
+        controls: Receiver<Control>,
+        taps: Receiver<Option<Tap>>,
+    ) -> Driver {
+        Driver {
+            engine,
+            states,
+            controls,
+            taps,
+            tap: None,
+        }
+    }
+
+    pub fn render(&mut self, data: &mut [f32], channels: usize) {
+        self.update();
+        // every pair of channels carries the tracks routed to it, a stereo
+        // device gets the whole mix, a single trailing channel repeats the
+        // last pair
+        let pairs = (channels / 2).clamp(1, SEQ_TRACK_COUNT);
+        let mut outputs = [0.0; SEQ_TRACK_COUNT];
+        for frame in data.chunks_mut(channels) {
+            self.engine.tick_outputs(&mut outputs[..pairs]);
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                *sample = outputs[(channel / 2).min(pairs - 1)];
+            }
+        }
+        if let Some(tap) = &mut self.tap {
+            tap.write(data);
+        }
+    }
+
+    // the mix and next to it every track on its own, one entry per frame
+    pub fn render_tracks(&mut self, mix: &mut [f32], tracks: &mut [[f32; SEQ_TRACK_COUNT]]) {
+        self.update();
+        for (out, frame) in mix.iter_mut().zip(tracks.iter_mut()) {
+            *out = self.engine.tick_tracks(frame);
+        }
+        // recorded like the stereo pair the mix goes out on
+        if let Some(tap) = &mut self.tap {
+            for &out in mix.iter() {
+                tap.write(&[out, out]);
+            }
+        }
+    }
+
+    fn update(&mut self) {
+        // only the latest state matters when several edits queued up
+        if let Some(state) = self.states.try_iter().last() {
+            self.engine.set_state(state);
+        }
+        while let Ok(control) = self.controls.try_recv() {
+            self.engine.control(control);
+        }
+        while let Ok(tap) = self.taps.try_recv() {
+            self.tap = tap;
+        }
+    }
+}
+
+pub enum Output {
+    Stream(Stream),
+    Null(NullOutput),
+    #[cfg(feature = "jack")]
+    Jack(JackOutput),
+}
+
+pub fn device_names() -> Result<Vec<String>> {
+    let host = cpal::default_host();
+    Ok(host
+        .output_devices()?
+        .filter_map(|device| device.name().ok())
+        .collect())
+}
+
+pub fn default_device_name() -> Option<String> {
+    cpal::default_host().default_output_device()?.name().ok()
+}
+
+// the configured output device and stream settings, anything that isn't
+// configured is left to the device's defaults
+pub fn open_output(settings: &AudioConfig) -> Result<(Device, StreamConfig)> {
+    let host = cpal::default_host();
+    let device = match &settings.device {
+        Some(name) => host
+            .output_devices()?
+            .find(|device| device.name().is_ok_and(|device| device == *name))
+            .ok_or_else(|| anyhow!("no such audio device: {}", name))?,
+        None => host
+            .default_output_device()
+            .ok_or_else(|| anyhow!("no audio output device"))?,
+    };
+    let name = device.name().unwrap_or_default();
+    let default = device
+        .default_output_config()
+        .with_context(|| format!("can't use {}", name))?;
+
+    let supported = match settings.sample_rate {
+        Some(rate) => device
+            .supported_output_configs()?
+            .filter(|range| {
+                range.channels() == default.channels()
+                    && range.sample_format() == default.sample_format()
+            })
+            .find_map(|range| range.try_with_sample_rate(SampleRate(rate)))
+            .ok_or_else(|| anyhow!("{} doesn't support {} Hz", name, rate))?,
+        None => default,
+    };
+    let mut config = supported.config();
+    if let Some(frames) = settings.buffer_size {
+        if let SupportedBufferSize::Range { min, max } = *supported.buffer_size() {
+            if !(min..=max).contains(&frames) {
+                bail!("buffer size must be between {} and {} frames", min, max);
+            }
+        }
+        config.buffer_size = BufferSize::Fixed(frames);
+    }
+    Ok((device, config))
+}
+
+pub fn start_stream(
+    device: &Device,
+    config: &StreamConfig,
+    mut driver: Driver,
+    errors: Sender<StreamError>,
+) -> Result<Stream> {
+    let channels = config.channels as usize;
+    let stream = device.build_output_stream(
+        config,
+        move |data: &mut [f32], _: &cpal::OutputCallbackInfo| driver.render(data, channels),
+        // errors are shown by the ui thread, printing them would garble the
+        // screen
+        move |err| {
+            let _ = errors.send(err);
+        },
+        None,
+    )?;
+    stream.play()?;
+    Ok(stream)
+}
+
+pub fn null_config(settings: &AudioConfig) -> StreamConfig {
+    StreamConfig {
+        channels: 2,
+        sample_rate: SampleRate(settings.sample_rate.unwrap_or(NULL_SAMPLE_RATE)),
+        buffer_size: BufferSize::Fixed(settings.buffer_size.unwrap_or(NULL_BUFFER_SIZE)),
+    }
+}
+
+// runs the engine in real time on a thread of its own and drops the audio,
+// for machines without a sound card and for tests
+pub struct NullOutput {
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl NullOutput {
+    pub fn start(mut driver: Driver, config: &StreamConfig) -> NullOutput {
+        let running = Arc::new(AtomicBool::new(true));
+        let frames = match config.buffer_size {
+            BufferSize::Fixed(frames) => frames,
+            BufferSize::Default => NULL_BUFFER_SIZE,
+        } as usize;
+        let channels = config.channels as usize;
+        let rate = config.sample_rate.0 as f64;
+
+        let thread = thread::spawn({
+            let running = running.clone();
+            move || {
+                let mut buffer = vec![0.0; frames * channels];
+                let start = Instant::now();
+                let mut rendered = 0;
+                while running.load(Ordering::Relaxed) {
+                    driver.render(&mut buffer, channels);
+                    rendered += frames;
+                    // keep the pace of a real device
+                    let due = start + Duration::from_secs_f64(rendered as f64 / rate);
+                    if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                        thread::sleep(wait);
+                    }
+                }
+            }
+        });
+        NullOutput {
+            running,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for NullOutput {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
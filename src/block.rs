@@ -0,0 +1,187 @@
+use crate::cell::{Cell, MAX_VALUE};
+use rand::Rng;
+
+// a rectangular selection of cells, stored column by column like the grid
+pub type Block = Vec<Vec<Cell>>;
+
+pub fn clear(block: &Block) -> Block {
+    block
+        .iter()
+        .map(|column| vec![Cell::Empty; column.len()])
+        .collect()
+}
+
+pub fn repeat(block: &Block, times: usize) -> Block {
+    // stack copies of the block on top of each other
+    block
+        .iter()
+        .map(|column| {
+            column
+                .iter()
+                .cycle()
+                .take(column.len() * times)
+                .cloned()
+                .collect()
+        })
+        .collect()
+}
+
+pub fn fill(block: &Block, every: usize, value: Cell) -> Block {
+    // write the value on every nth step, leaving the steps in between alone
+    block
+        .iter()
+        .map(|column| {
+            column
+                .iter()
+                .enumerate()
+                .map(|(idx, cell)| if idx % every == 0 { value } else { *cell })
+                .collect()
+        })
+        .collect()
+}
+
+pub fn transpose(block: &Block, amount: i32) -> Block {
+    block
+        .iter()
+        .map(|column| {
+            column
+                .iter()
+                .map(|cell| match cell.value() {
+                    Some(value) => cell.with_value(value + amount),
+                    None => *cell,
+                })
+                .collect()
+        })
+        .collect()
+}
+
+pub fn reverse(block: &Block) -> Block {
+    block
+        .iter()
+        .map(|column| column.iter().rev().cloned().collect())
+        .collect()
+}
+
+pub fn interpolate(block: &Block) -> Block {
+    // fill each column with a linear ramp between its first and last value
+    block
+        .iter()
+        .map(|column| {
+            let (Some(first), Some(last)) = (column.first(), column.last()) else {
+                return column.clone();
+            };
+            let steps = (column.len() - 1).max(1) as f32;
+            let lerp = |from: i32, to: i32, idx: usize| {
+                (from as f32 + (to - from) as f32 * idx as f32 / steps).round() as i32
+            };
+            match (first.value(), last.value()) {
+                (Some(from), Some(to)) if first.same_kind(last) => (0..column.len())
+                    .map(|idx| first.with_value(lerp(from, to, idx)))
+                    .collect(),
+                _ => column.clone(),
+            }
+        })
+        .collect()
+}
+
+pub fn randomize(block: &Block) -> Block {
+    // only cells that hold a value are randomized, pitches stay in their octave
+    let mut rng = rand::thread_rng();
+    block
+        .iter()
+        .map(|column| {
+            column
+                .iter()
+                .map(|cell| match *cell {
+                    Cell::Param(_) | Cell::Velocity(_) => {
+                        cell.with_value(rng.gen_range(0..=MAX_VALUE) as i32)
+                    }
+                    Cell::Pitch(pitch) => {
+                        let root = pitch - pitch % 12;
+                        Cell::Pitch((root + rng.gen_range(0..12)).min(127))
+                    }
+                    _ => *cell,
+                })
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cell::CellKind;
+
+    const EMPTY: &str = "___";
+
+    fn block(columns: &[&[&str]]) -> Block {
+        // the first column holds pitches and the others parameters, like a track
+        columns
+            .iter()
+            .enumerate()
+            .map(|(col, column)| {
+                column
+                    .iter()
+                    .map(|cell| Cell::parse(cell, CellKind::of(col)).unwrap())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_transpose() {
+        assert_eq!(
+            transpose(&block(&[&["C3", "B3", EMPTY], &["50", "0", "99"]]), 1),
+            block(&[&["C#3", "C4", EMPTY], &["51", "1", "99"]])
+        );
+        assert_eq!(
+            transpose(&block(&[&["C0", "D3"], &["0", "V10"]]), -1),
+            block(&[&["C0", "C#3"], &["0", "V09"]])
+        );
+    }
+
+    #[test]
+    fn test_fill() {
+        assert_eq!(
+            fill(
+                &block(&[&[EMPTY, "D3", EMPTY, EMPTY, "E3"]]),
+                2,
+                Cell::Pitch(48)
+            ),
+            block(&[&["C3", "D3", "C3", EMPTY, "C3"]])
+        );
+    }
+
+    #[test]
+    fn test_reverse() {
+        assert_eq!(
+            reverse(&block(&[&["C3", EMPTY, "E3"], &["1", "2", "3"]])),
+            block(&[&["E3", EMPTY, "C3"], &["3", "2", "1"]])
+        );
+    }
+
+    #[test]
+    fn test_interpolate() {
+        assert_eq!(
+            interpolate(&block(&[
+                &["C3", EMPTY, EMPTY, "D#3"],
+                &["0", EMPTY, EMPTY, "99"],
+                &["V10", EMPTY, EMPTY, "10"],
+            ])),
+            block(&[
+                &["C3", "C#3", "D3", "D#3"],
+                &["0", "33", "66", "99"],
+                &["V10", EMPTY, EMPTY, "10"],
+            ])
+        );
+    }
+
+    #[test]
+    fn test_randomize_keeps_empty_cells() {
+        let randomized = randomize(&block(&[&["C3", EMPTY], &[EMPTY, "50"]]));
+        assert_eq!(randomized[0][1], Cell::Empty);
+        assert_eq!(randomized[1][0], Cell::Empty);
+        assert!(matches!(randomized[0][0], Cell::Pitch(48..=59)));
+        assert!(matches!(randomized[1][1], Cell::Param(0..=99)));
+    }
+}
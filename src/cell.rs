@@ -0,0 +1,222 @@
+use anyhow::{anyhow, bail, Result};
+use std::fmt;
+
+use crate::engine::ENGINE_COUNT;
+
+pub const PITCHES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+// C0, the lowest note that can be written as a note name
+pub const MIN_PITCH: u8 = 12;
+pub const MAX_PITCH: u8 = 127;
+// parameters, velocities and morph are written as two digits
+pub const MAX_VALUE: u8 = 99;
+const EMPTY_TEXT: &str = "___";
+
+// what the engine gets for a parameter value, 50 is the middle
+pub fn scale(value: u8) -> f32 {
+    value as f32 / (MAX_VALUE + 1) as f32
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Cell {
+    #[default]
+    Empty,
+    Pitch(u8),
+    Velocity(u8),
+    Param(u8),
+    Fx(Fx),
+}
+
+// commands in a parameter column that change the note instead of setting
+// the parameter, written as a letter followed by two digits
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Fx {
+    Engine(u8),
+    Morph(u8),
+}
+
+// the first column of every track holds pitches, the other two parameters
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CellKind {
+    Pitch,
+    Param,
+}
+
+impl CellKind {
+    pub fn of(col: usize) -> CellKind {
+        match col % 3 {
+            0 => CellKind::Pitch,
+            _ => CellKind::Param,
+        }
+    }
+}
+
+impl Cell {
+    pub fn parse(text: &str, kind: CellKind) -> Result<Cell> {
+        let text = text.trim();
+        if text.is_empty() || text == EMPTY_TEXT {
+            return Ok(Cell::Empty);
+        }
+        match kind {
+            CellKind::Pitch => {
+                // pitches can also be given as midi note numbers
+                let pitch = match text.parse::<i32>() {
+                    Ok(pitch) => pitch,
+                    Err(_) => parse_pitch(text).ok_or_else(|| anyhow!("not a note: {}", text))?,
+                };
+                if !(MIN_PITCH as i32..=MAX_PITCH as i32).contains(&pitch) {
+                    bail!("note out of range: {}", text);
+                }
+                Ok(Cell::Pitch(pitch as u8))
+            }
+            CellKind::Param => {
+                let (prefix, digits) =
+                    text.split_at(text.find(|c: char| c.is_ascii_digit()).unwrap_or(0));
+                let value = digits
+                    .parse::<u8>()
+                    .map_err(|_| anyhow!("not a value: {}", text))?;
+                let max = match prefix {
+                    "E" | "e" => ENGINE_COUNT as u8 - 1,
+                    _ => MAX_VALUE,
+                };
+                if value > max {
+                    bail!("{} must be between 0 and {}", text, max);
+                }
+                match prefix {
+                    "" => Ok(Cell::Param(value)),
+                    "V" | "v" => Ok(Cell::Velocity(value)),
+                    "E" | "e" => Ok(Cell::Fx(Fx::Engine(value))),
+                    "M" | "m" => Ok(Cell::Fx(Fx::Morph(value))),
+                    _ => bail!("not a value: {}", text),
+                }
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Cell::Empty
+    }
+
+    // whether the cell can be written into a column of the given kind
+    pub fn fits(&self, kind: CellKind) -> bool {
+        match self {
+            Cell::Empty => true,
+            Cell::Pitch(_) => kind == CellKind::Pitch,
+            _ => kind == CellKind::Param,
+        }
+    }
+
+    pub fn value(&self) -> Option<i32> {
+        match *self {
+            Cell::Empty => None,
+            Cell::Pitch(value)
+            | Cell::Velocity(value)
+            | Cell::Param(value)
+            | Cell::Fx(Fx::Engine(value) | Fx::Morph(value)) => Some(value as i32),
+        }
+    }
+
+    // the same kind of cell with another value, clamped to what it can hold
+    pub fn with_value(&self, value: i32) -> Cell {
+        let clamp = |min: u8, max: u8| value.clamp(min as i32, max as i32) as u8;
+        match self {
+            Cell::Empty => Cell::Empty,
+            Cell::Pitch(_) => Cell::Pitch(clamp(MIN_PITCH, MAX_PITCH)),
+            Cell::Velocity(_) => Cell::Velocity(clamp(0, MAX_VALUE)),
+            Cell::Param(_) => Cell::Param(clamp(0, MAX_VALUE)),
+            Cell::Fx(Fx::Engine(_)) => Cell::Fx(Fx::Engine(clamp(0, ENGINE_COUNT as u8 - 1))),
+            Cell::Fx(Fx::Morph(_)) => Cell::Fx(Fx::Morph(clamp(0, MAX_VALUE))),
+        }
+    }
+
+    pub fn same_kind(&self, other: &Cell) -> bool {
+        self.with_value(0) == other.with_value(0)
+    }
+}
+
+impl fmt::Display for Cell {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Cell::Empty => write!(f, "{}", EMPTY_TEXT),
+            Cell::Pitch(pitch) => write!(f, "{}", format_pitch(*pitch as i32)),
+            Cell::Velocity(velocity) => write!(f, "V{:02}", velocity),
+            Cell::Param(value) => write!(f, "{:02}", value),
+            Cell::Fx(Fx::Engine(engine)) => write!(f, "E{:02}", engine),
+            Cell::Fx(Fx::Morph(morph)) => write!(f, "M{:02}", morph),
+        }
+    }
+}
+
+pub fn format_pitch(pitch: i32) -> String {
+    // inverse of parse_pitch, C0 is pitch 12
+    let name = PITCHES[pitch.rem_euclid(12) as usize];
+    format!("{}{}", name, pitch / 12 - 1)
+}
+
+pub fn parse_pitch(input: &str) -> Option<i32> {
+    // a note name like C or c# followed by the octave
+    let len = input.find(|c: char| c.is_ascii_digit())?;
+    let (name, octave) = input.split_at(len);
+    let idx = PITCHES
+        .iter()
+        .position(|pitch| pitch.eq_ignore_ascii_case(name))?;
+    let octave = octave.parse::<i32>().ok()?;
+    Some(idx as i32 + (octave + 1) * 12)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_pitch() {
+        assert_eq!(format_pitch(12), "C0");
+        assert_eq!(format_pitch(49), "C#3");
+        assert_eq!(format_pitch(70), "A#4");
+        for pitch in 12..128 {
+            assert_eq!(parse_pitch(&format_pitch(pitch)), Some(pitch));
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Cell::parse("c#3", CellKind::Pitch).unwrap(),
+            Cell::Pitch(49)
+        );
+        assert_eq!(Cell::parse("60", CellKind::Pitch).unwrap(), Cell::Pitch(60));
+        assert_eq!(Cell::parse("___", CellKind::Pitch).unwrap(), Cell::Empty);
+        assert_eq!(Cell::parse("5", CellKind::Param).unwrap(), Cell::Param(5));
+        assert_eq!(
+            Cell::parse("v80", CellKind::Param).unwrap(),
+            Cell::Velocity(80)
+        );
+        assert_eq!(
+            Cell::parse("E12", CellKind::Param).unwrap(),
+            Cell::Fx(Fx::Engine(12))
+        );
+
+        assert!(Cell::parse("C3", CellKind::Param).is_err());
+        assert!(Cell::parse("H3", CellKind::Pitch).is_err());
+        assert!(Cell::parse("B9", CellKind::Pitch).is_err());
+        assert!(Cell::parse("100", CellKind::Param).is_err());
+        assert!(Cell::parse("E30", CellKind::Param).is_err());
+        assert!(Cell::parse("X10", CellKind::Param).is_err());
+    }
+
+    #[test]
+    fn test_display_roundtrip() {
+        for cell in [
+            Cell::Empty,
+            Cell::Pitch(61),
+            Cell::Velocity(7),
+            Cell::Param(0),
+            Cell::Fx(Fx::Morph(99)),
+        ] {
+            let kind = CellKind::of(if cell.fits(CellKind::Pitch) { 0 } else { 1 });
+            assert_eq!(Cell::parse(&cell.to_string(), kind).unwrap(), cell);
+        }
+        assert_eq!(Cell::Param(5).to_string(), "05");
+    }
+}
@@ -0,0 +1,432 @@
+use anyhow::{anyhow, bail, Result};
+use regex::{Regex, RegexBuilder};
+use std::time::Duration;
+
+// every command name understood in command mode, used for tab completion
+pub const COMMANDS: [&str; 34] = [
+    "bpm",
+    "clear",
+    "d",
+    "devices",
+    "e",
+    "earlier",
+    "edit",
+    "engine",
+    "export",
+    "fill",
+    "glide",
+    "imap",
+    "interpolate",
+    "later",
+    "len",
+    "map",
+    "nmap",
+    "octave",
+    "q",
+    "quit",
+    "rec",
+    "reg",
+    "registers",
+    "route",
+    "s",
+    "set",
+    "source",
+    "step",
+    "substitute",
+    "transpose",
+    "vmap",
+    "w",
+    "wq",
+    "y",
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Address {
+    Number(usize),
+    Current,
+    Last,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Range {
+    All,
+    Rows(Address, Address),
+    // the last visual block, written as '<,'>
+    Visual,
+}
+
+impl Range {
+    // resolve to zero based, inclusive row indices
+    pub fn rows(&self, current: usize, len: usize) -> Result<(usize, usize)> {
+        let resolve = |address: Address| match address {
+            Address::Number(n) if (1..=len).contains(&n) => Ok(n - 1),
+            Address::Number(n) => Err(anyhow!("step {} out of range", n)),
+            Address::Current => Ok(current),
+            Address::Last => Ok(len - 1),
+        };
+        match *self {
+            Range::All => Ok((0, len - 1)),
+            Range::Visual => bail!("no visual selection"),
+            Range::Rows(start, end) => {
+                let (start, end) = (resolve(start)?, resolve(end)?);
+                if start > end {
+                    bail!("backwards range");
+                }
+                Ok((start, end))
+            }
+        }
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct ExCommand {
+    pub range: Option<Range>,
+    pub name: String,
+    pub bang: bool,
+    pub args: Vec<String>,
+}
+
+impl ExCommand {
+    pub fn parse(input: &str) -> Result<ExCommand> {
+        let input = input.trim().trim_start_matches(':').trim_start();
+        let (range, rest) = Self::parse_range(input)?;
+
+        let name_len = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len());
+        let (name, rest) = rest.split_at(name_len);
+        // a range on its own jumps to that step
+        if name.is_empty() && (range.is_none() || !rest.trim().is_empty()) {
+            bail!("not an editor command: {}", input);
+        }
+        let (bang, rest) = match rest.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+
+        Ok(ExCommand {
+            range,
+            name: name.to_string(),
+            bang,
+            args: rest.split_whitespace().map(|arg| arg.to_string()).collect(),
+        })
+    }
+
+    fn parse_range(input: &str) -> Result<(Option<Range>, &str)> {
+        if let Some(rest) = input.strip_prefix('%') {
+            return Ok((Some(Range::All), rest));
+        }
+        if let Some(rest) = input.strip_prefix("'<,'>") {
+            return Ok((Some(Range::Visual), rest));
+        }
+        let (start, rest) = match Self::parse_address(input)? {
+            (Some(start), rest) => (start, rest),
+            (None, rest) => return Ok((None, rest)),
+        };
+        match rest.strip_prefix(',') {
+            Some(rest) => match Self::parse_address(rest)? {
+                (Some(end), rest) => Ok((Some(Range::Rows(start, end)), rest)),
+                (None, _) => bail!("incomplete range"),
+            },
+            None => Ok((Some(Range::Rows(start, start)), rest)),
+        }
+    }
+
+    fn parse_address(input: &str) -> Result<(Option<Address>, &str)> {
+        if let Some(rest) = input.strip_prefix('.') {
+            return Ok((Some(Address::Current), rest));
+        }
+        if let Some(rest) = input.strip_prefix('$') {
+            return Ok((Some(Address::Last), rest));
+        }
+        let digits = input
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(input.len());
+        if digits == 0 {
+            return Ok((None, input));
+        }
+        let (number, rest) = input.split_at(digits);
+        let number = number
+            .parse()
+            .map_err(|_| anyhow!("invalid step: {}", number))?;
+        Ok((Some(Address::Number(number)), rest))
+    }
+
+    pub fn arg<T: std::str::FromStr>(&self, idx: usize, what: &str) -> Result<T> {
+        let arg = self
+            .args
+            .get(idx)
+            .ok_or_else(|| anyhow!("{} requires a {}", self.name, what))?;
+        arg.parse()
+            .map_err(|_| anyhow!("invalid {}: {}", what, arg))
+    }
+}
+
+pub fn search_pattern(pattern: &str) -> Result<Regex> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .build()
+        .map_err(|_| anyhow!("invalid pattern: {}", pattern))
+}
+
+// :s/pattern/replacement/flags, where any punctuation can be the delimiter
+pub struct Substitute {
+    pub pattern: Regex,
+    pub replacement: String,
+    pub global: bool,
+}
+
+impl Substitute {
+    pub fn parse(input: &str) -> Result<Substitute> {
+        let mut chars = input.chars();
+        let delimiter = match chars.next() {
+            Some(delimiter) if delimiter.is_ascii_punctuation() => delimiter,
+            _ => bail!("expected /pattern/replacement/"),
+        };
+        let parts = chars.as_str().split(delimiter).collect::<Vec<&str>>();
+        let (pattern, replacement, flags) = match parts.as_slice() {
+            [pattern] => (*pattern, "", ""),
+            [pattern, replacement] => (*pattern, *replacement, ""),
+            [pattern, replacement, flags] => (*pattern, *replacement, *flags),
+            _ => bail!("trailing characters: {}", input),
+        };
+        if pattern.is_empty() {
+            bail!("empty pattern");
+        }
+        if let Some(flag) = flags.chars().find(|&flag| flag != 'g') {
+            bail!("unknown flag: {}", flag);
+        }
+
+        Ok(Substitute {
+            pattern: search_pattern(pattern)?,
+            replacement: replacement.to_string(),
+            global: flags.contains('g'),
+        })
+    }
+
+    pub fn replace(&self, cell: &str) -> Option<String> {
+        if !self.pattern.is_match(cell) {
+            return None;
+        }
+        // like vim, without g only the first match is replaced
+        let replacement = self.replacement.as_str();
+        let replaced = if self.global {
+            self.pattern.replace_all(cell, replacement)
+        } else {
+            self.pattern.replace(cell, replacement)
+        };
+        Some(replaced.into_owned())
+    }
+}
+
+// the argument of :earlier and :later, either a number of changes or a time
+// span like 30s, 5m or 1h
+#[derive(PartialEq, Debug)]
+pub enum Steps {
+    Count(usize),
+    Time(Duration),
+}
+
+impl Steps {
+    pub fn parse(input: Option<&str>) -> Result<Steps> {
+        let Some(input) = input else {
+            return Ok(Steps::Count(1));
+        };
+        let digits = input
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(input.len());
+        let (number, unit) = input.split_at(digits);
+        let number = number
+            .parse::<u64>()
+            .map_err(|_| anyhow!("invalid count: {}", input))?;
+        let seconds = |scale: u64| {
+            number
+                .checked_mul(scale)
+                .map(|secs| Steps::Time(Duration::from_secs(secs)))
+                .ok_or_else(|| anyhow!("invalid count: {}", input))
+        };
+        match unit {
+            "" => Ok(Steps::Count(number as usize)),
+            "s" => seconds(1),
+            "m" => seconds(60),
+            "h" => seconds(60 * 60),
+            _ => bail!("invalid count: {}", input),
+        }
+    }
+}
+
+// complete the command name at the start of the line, returning the new
+// line and the candidates if the prefix is ambiguous
+pub fn complete(line: &str) -> (String, Vec<&'static str>) {
+    let Some(input) = line.strip_prefix(':') else {
+        return (line.to_string(), vec![]);
+    };
+    let (range, name) = input.split_at(
+        input
+            .find(|c: char| c.is_ascii_alphabetic())
+            .unwrap_or(input.len()),
+    );
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphabetic()) {
+        return (line.to_string(), vec![]);
+    }
+
+    let candidates = COMMANDS
+        .iter()
+        .copied()
+        .filter(|command| command.starts_with(name))
+        .collect::<Vec<&str>>();
+    let completed = match candidates.as_slice() {
+        [] => return (line.to_string(), vec![]),
+        [command] => format!("{} ", command),
+        [first, rest @ ..] => {
+            // extend to the longest prefix shared by all candidates
+            let mut prefix = first.to_string();
+            for command in rest {
+                while !command.starts_with(prefix.as_str()) {
+                    prefix.pop();
+                }
+            }
+            prefix
+        }
+    };
+    let candidates = if candidates.len() > 1 {
+        candidates
+    } else {
+        vec![]
+    };
+    (format!(":{}{}", range, completed), candidates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            ExCommand::parse(":4,8d").unwrap(),
+            ExCommand {
+                range: Some(Range::Rows(Address::Number(4), Address::Number(8))),
+                name: "d".to_string(),
+                bang: false,
+                args: vec![],
+            }
+        );
+        assert_eq!(
+            ExCommand::parse(":q!").unwrap(),
+            ExCommand {
+                range: None,
+                name: "q".to_string(),
+                bang: true,
+                args: vec![],
+            }
+        );
+        assert_eq!(
+            ExCommand::parse(":.,$transpose -12").unwrap(),
+            ExCommand {
+                range: Some(Range::Rows(Address::Current, Address::Last)),
+                name: "transpose".to_string(),
+                bang: false,
+                args: vec!["-12".to_string()],
+            }
+        );
+        assert_eq!(
+            ExCommand::parse(":12").unwrap(),
+            ExCommand {
+                range: Some(Range::Rows(Address::Number(12), Address::Number(12))),
+                name: "".to_string(),
+                bang: false,
+                args: vec![],
+            }
+        );
+        assert!(ExCommand::parse(":4,").is_err());
+        assert!(ExCommand::parse(":").is_err());
+        assert!(ExCommand::parse(":!!").is_err());
+    }
+
+    #[test]
+    fn test_visual_range() {
+        let cmd = ExCommand::parse(":'<,'>s/C3/D3/g").unwrap();
+        assert_eq!(cmd.range, Some(Range::Visual));
+        assert_eq!(cmd.name, "s");
+        assert_eq!(cmd.args, vec!["/C3/D3/g"]);
+    }
+
+    #[test]
+    fn test_substitute() {
+        let sub = Substitute::parse("/c3/D3/g").unwrap();
+        assert!(sub.global);
+        assert_eq!(sub.replace("C3"), Some("D3".to_string()));
+        assert_eq!(sub.replace("C#3"), None);
+
+        let sub = Substitute::parse("|C|D|").unwrap();
+        assert!(!sub.global);
+        assert_eq!(sub.replace("C3"), Some("D3".to_string()));
+
+        assert_eq!(
+            Substitute::parse("/0/1/").unwrap().replace("100"),
+            Some("110".to_string())
+        );
+        assert_eq!(
+            Substitute::parse("/0/1/g").unwrap().replace("100"),
+            Some("111".to_string())
+        );
+
+        assert!(Substitute::parse("C3/D3").is_err());
+        assert!(Substitute::parse("//D3/").is_err());
+        assert!(Substitute::parse("/C3/D3/x").is_err());
+        assert!(Substitute::parse("/(/D3/").is_err());
+    }
+
+    #[test]
+    fn test_range_rows() {
+        assert_eq!(Range::All.rows(3, 16).unwrap(), (0, 15));
+        assert_eq!(
+            Range::Rows(Address::Number(4), Address::Last)
+                .rows(0, 16)
+                .unwrap(),
+            (3, 15)
+        );
+        assert!(Range::Rows(Address::Number(17), Address::Number(17))
+            .rows(0, 16)
+            .is_err());
+        assert!(Range::Rows(Address::Number(8), Address::Number(4))
+            .rows(0, 16)
+            .is_err());
+    }
+
+    #[test]
+    fn test_steps() {
+        assert_eq!(Steps::parse(None).unwrap(), Steps::Count(1));
+        assert_eq!(Steps::parse(Some("4")).unwrap(), Steps::Count(4));
+        assert!(Steps::parse(Some("99999999999999999h")).is_err());
+        assert_eq!(
+            Steps::parse(Some("30s")).unwrap(),
+            Steps::Time(Duration::from_secs(30))
+        );
+        assert_eq!(
+            Steps::parse(Some("2m")).unwrap(),
+            Steps::Time(Duration::from_secs(120))
+        );
+        assert!(Steps::parse(Some("s")).is_err());
+        assert!(Steps::parse(Some("3d")).is_err());
+    }
+
+    #[test]
+    fn test_complete() {
+        assert_eq!(complete(":tr"), (":transpose ".to_string(), vec![]));
+        assert_eq!(complete(":%tr"), (":%transpose ".to_string(), vec![]));
+        assert_eq!(
+            complete(":e"),
+            (
+                ":e".to_string(),
+                vec!["e", "earlier", "edit", "engine", "export"]
+            )
+        );
+        assert_eq!(
+            complete(":re"),
+            (":re".to_string(), vec!["rec", "reg", "registers"])
+        );
+        assert_eq!(complete(":xyz"), (":xyz".to_string(), vec![]));
+    }
+}
@@ -0,0 +1,206 @@
+use anyhow::{bail, Context, Result};
+use crossterm::style::Color;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use crate::audio::Backend;
+use crate::engine::{DEFAULT_BPM, DEFAULT_ENGINE, ENGINE_COUNT, SEQ_TRACK_COUNT};
+
+// settings read from ~/.config/bl8/config.toml, every one of them optional
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bpm: f32,
+    pub engines: [usize; SEQ_TRACK_COUNT],
+    // the output pair of every track on devices with more than two channels,
+    // 1 for channels 1/2, 2 for 3/4 and so on
+    pub routes: [usize; SEQ_TRACK_COUNT],
+    pub edit_step: usize,
+    pub audio: AudioConfig,
+    pub colors: Colors,
+    pub keys: KeyRemaps,
+    pub map: Keymaps,
+}
+
+// anything left unset is up to the output device
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioConfig {
+    pub backend: Backend,
+    // output device name as listed by :devices
+    pub device: Option<String>,
+    pub sample_rate: Option<u32>,
+    // in frames
+    pub buffer_size: Option<u32>,
+    // play and stop along with the jack transport, otherwise play right away.
+    // off by default as nothing rolls the transport on a plain jack setup
+    pub transport: bool,
+}
+
+// colours are named like red or dark_grey, or written as #rrggbb or ansi_(n)
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Colors {
+    pub tracks: [Color; SEQ_TRACK_COUNT],
+    pub empty: Color,
+    pub beat: Color,
+    pub playhead: Color,
+}
+
+// key sequences bound to named actions per mode like :map binds them, e.g.
+// `"ù" = "undo"` in [map.normal], insert mode binds single keys to notes like
+// note0
+#[derive(Deserialize, Debug, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Keymaps {
+    pub normal: HashMap<String, String>,
+    pub visual: HashMap<String, String>,
+    pub insert: HashMap<String, String>,
+}
+
+// keys typed in a mode are replaced by other keys before they are handled,
+// e.g. `"é" = "2"` in [keys.normal] for a count on an azerty keyboard. unlike
+// [map] this also covers keys that aren't actions, like counts, registers and
+// the note and hex digits typed in insert mode
+#[derive(Deserialize, Debug, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyRemaps {
+    pub normal: HashMap<char, String>,
+    pub visual: HashMap<char, String>,
+    pub insert: HashMap<char, String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            bpm: DEFAULT_BPM,
+            engines: [DEFAULT_ENGINE; SEQ_TRACK_COUNT],
+            routes: [1; SEQ_TRACK_COUNT],
+            edit_step: 1,
+            audio: AudioConfig::default(),
+            colors: Colors::default(),
+            keys: KeyRemaps::default(),
+            map: Keymaps::default(),
+        }
+    }
+}
+
+impl Default for AudioConfig {
+    fn default() -> AudioConfig {
+        AudioConfig {
+            backend: Backend::Cpal,
+            device: None,
+            sample_rate: None,
+            buffer_size: None,
+            transport: false,
+        }
+    }
+}
+
+impl Default for Colors {
+    fn default() -> Colors {
+        Colors {
+            tracks: [
+                Color::Red,
+                Color::Yellow,
+                Color::Green,
+                Color::Cyan,
+                Color::Blue,
+                Color::Magenta,
+                Color::DarkCyan,
+                Color::DarkYellow,
+            ],
+            empty: Color::DarkGrey,
+            beat: Color::AnsiValue(236),
+            playhead: Color::AnsiValue(53),
+        }
+    }
+}
+
+impl Config {
+    // $XDG_CONFIG_HOME/bl8/config.toml, falling back to ~/.config
+    pub fn path() -> Option<PathBuf> {
+        let dir = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+        Some(dir.join("bl8").join("config.toml"))
+    }
+
+    pub fn load(path: &Path) -> Result<Config> {
+        let text =
+            fs::read_to_string(path).with_context(|| format!("can't read {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("can't load {}", path.display()))
+    }
+
+    fn parse(text: &str) -> Result<Config> {
+        let config: Config = toml::from_str(text)?;
+        // bpm and edit step are checked like :set checks them, engines here
+        if let Some(engine) = config
+            .engines
+            .iter()
+            .find(|&&engine| engine >= ENGINE_COUNT)
+        {
+            bail!("invalid engine: {}", engine);
+        }
+        if let Some(output) = config
+            .routes
+            .iter()
+            .find(|&&output| !(1..=SEQ_TRACK_COUNT).contains(&output))
+        {
+            bail!("invalid output pair: {}", output);
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+
+        let config = Config::parse(
+            "bpm = 98.5\nedit_step = 2\n\n[audio]\nbackend = \"null\"\ndevice = \"pulse\"\nsample_rate = 44100\n\n\
+             [colors]\nempty = \"grey\"\nplayhead = \"#302040\"\n\n\
+             [keys.normal]\n\"é\" = \"2\"\n\n[map.normal]\ngu = \"undo\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.bpm, 98.5);
+        assert_eq!(config.edit_step, 2);
+        assert_eq!(config.engines, [DEFAULT_ENGINE; SEQ_TRACK_COUNT]);
+        assert_eq!(config.routes, [1; SEQ_TRACK_COUNT]);
+        assert_eq!(config.audio.backend, Backend::Null);
+        assert_eq!(config.audio.device.as_deref(), Some("pulse"));
+        assert_eq!(config.audio.sample_rate, Some(44100));
+        assert_eq!(config.audio.buffer_size, None);
+        assert_eq!(config.colors.empty, Color::Grey);
+        assert_eq!(
+            config.colors.playhead,
+            Color::Rgb {
+                r: 0x30,
+                g: 0x20,
+                b: 0x40
+            }
+        );
+        assert_eq!(config.colors.tracks, Colors::default().tracks);
+        assert_eq!(config.keys.normal[&'é'], "2");
+        assert_eq!(config.map.normal["gu"], "undo");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Config::parse("bpm = \"fast\"").is_err());
+        assert!(Config::parse("tempo = 120").is_err());
+        assert!(Config::parse("engines = [1, 1, 1, 1, 1, 1, 1, 99]").is_err());
+        assert!(Config::parse("routes = [1, 1, 1, 2, 2, 3, 4, 0]").is_err());
+        assert!(Config::parse("[colors]\nempty = \"greyish\"").is_err());
+        assert!(Config::parse("[keys.normal]\nab = \"c\"").is_err());
+    }
+}
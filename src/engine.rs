@@ -1,12 +1,33 @@
 use crate::limiter::Limiter;
 use crate::utils::midi_to_freq;
+use anyhow::{bail, Result};
 use crossbeam::channel::*;
 use mi_plaits_dsp::dsp::drums::*;
 use mi_plaits_dsp::dsp::voice::{Modulations, Patch, Voice};
 
 pub const SEQ_TRACK_COUNT: usize = 8;
 pub const INITIAL_STEP_COUNT: usize = 16;
+pub const MAX_STEP_COUNT: usize = 128;
+pub const DRUM_TRACK_COUNT: usize = 3;
+pub const ENGINE_COUNT: usize = 24;
+pub const DEFAULT_BPM: f32 = 120.0;
+pub const MIN_BPM: f32 = 20.0;
+pub const MAX_BPM: f32 = 300.0;
+pub const DEFAULT_ENGINE: usize = 1;
+// for jack ports and stem files
+pub const TRACK_NAMES: [&str; SEQ_TRACK_COUNT] = [
+    "kick", "snare", "hihat", "synth4", "synth5", "synth6", "synth7", "synth8",
+];
+// steps are sixteenth notes
+const STEPS_PER_BEAT: f32 = 4.0;
 const BLOCK_SIZE: usize = 1;
+// the level every track is mixed at
+pub const TRACK_GAIN: f32 = 1.0 / 3.0;
+// only the drums are in the mix and on the output pairs so far, the synth
+// tracks are heard on their jack ports and stems
+const MIX_TRACK_COUNT: usize = DRUM_TRACK_COUNT;
+// the rate the plaits voices are tuned for
+const PLAITS_SAMPLE_RATE: f32 = 48000.0;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Params {
@@ -25,6 +46,43 @@ impl Params {
             timbre: None,
         }
     }
+
+    pub fn get(&self, param: Param) -> Option<f32> {
+        match param {
+            Param::Harmonics => self.harmonics,
+            Param::Timbre => self.timbre,
+            Param::Morph => self.morph,
+        }
+    }
+}
+
+// the sound parameters that can be tweaked live and glide between locks,
+// drums only have the first two
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Param {
+    Harmonics,
+    Timbre,
+    Morph,
+}
+
+pub const PARAM_COUNT: usize = 3;
+pub const PARAMS: [Param; PARAM_COUNT] = [Param::Harmonics, Param::Timbre, Param::Morph];
+
+impl Param {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Param::Harmonics => "harmonics",
+            Param::Timbre => "timbre",
+            Param::Morph => "morph",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Param> {
+        match PARAMS.iter().find(|param| param.name() == name) {
+            Some(&param) => Ok(param),
+            None => bail!("unknown parameter: {}", name),
+        }
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Debug)]
@@ -48,27 +106,75 @@ impl Note {
 
 #[derive(Clone, Debug)]
 pub struct Track {
-    pub notes: [Option<Note>; 16],
+    pub notes: Vec<Option<Note>>,
 }
 
 pub type State = [Track; SEQ_TRACK_COUNT];
 
+// settings sent from the ui that are not part of the pattern
+pub enum Control {
+    Bpm(f32),
+    Engine {
+        track: usize,
+        engine: usize,
+    },
+    // the output pair a track plays on, counted from zero
+    Route {
+        track: usize,
+        output: usize,
+    },
+    // what a track's notes play with unless a step locks another value
+    Param {
+        track: usize,
+        param: Param,
+        value: f32,
+    },
+    // glide from every locked value to the next instead of stepping
+    Glide {
+        track: usize,
+        param: Param,
+        on: bool,
+    },
+}
+
+// a parameter on its way from one locked value to the next
+#[derive(Clone, Copy, Default)]
+struct Ramp {
+    value: f32,
+    delta: f32,
+    samples: u64,
+}
+
+impl Ramp {
+    #[inline]
+    fn tick(&mut self) -> Option<f32> {
+        if self.samples == 0 {
+            return None;
+        }
+        self.samples -= 1;
+        self.value += self.delta;
+        Some(self.value)
+    }
+}
+
 struct Kick {
     engine: analog_bass_drum::AnalogBassDrum,
     pitch: i8,
     trigger: bool,
     p1: f32,
     p2: f32,
+    sample_rate: f32,
 }
 
 impl Kick {
-    pub fn new() -> Self {
+    pub fn new(sample_rate: f32) -> Self {
         return Self {
             engine: analog_bass_drum::AnalogBassDrum::new(),
             pitch: 40,
             trigger: false,
             p1: 0.5,
             p2: 0.5,
+            sample_rate,
         };
     }
 
@@ -76,7 +182,7 @@ impl Kick {
     fn tick(&mut self) -> f32 {
         let mut out = [0.0; BLOCK_SIZE];
 
-        let f0 = midi_to_freq(self.pitch) / 48000.0;
+        let f0 = midi_to_freq(self.pitch) / self.sample_rate;
         self.engine.render(
             false,
             self.trigger,
@@ -105,16 +211,18 @@ struct Snare {
     trigger: bool,
     p1: f32,
     p2: f32,
+    sample_rate: f32,
 }
 
 impl Snare {
-    pub fn new() -> Self {
+    pub fn new(sample_rate: f32) -> Self {
         return Self {
             engine: analog_snare_drum::AnalogSnareDrum::new(),
             pitch: 40,
             trigger: false,
             p1: 0.5,
             p2: 0.5,
+            sample_rate,
         };
     }
 
@@ -122,7 +230,7 @@ impl Snare {
     fn tick(&mut self) -> f32 {
         let mut out = [0.0; BLOCK_SIZE];
 
-        let f0 = midi_to_freq(self.pitch) / 48000.0;
+        let f0 = midi_to_freq(self.pitch) / self.sample_rate;
         self.engine.render(
             false,
             self.trigger,
@@ -150,16 +258,18 @@ struct Hihat {
     trigger: bool,
     p1: f32,
     p2: f32,
+    sample_rate: f32,
 }
 
 impl Hihat {
-    pub fn new() -> Self {
+    pub fn new(sample_rate: f32) -> Self {
         return Self {
             engine: hihat::Hihat::new(),
             pitch: 40,
             trigger: false,
             p1: 0.5,
             p2: 0.5,
+            sample_rate,
         };
     }
 
@@ -169,7 +279,7 @@ impl Hihat {
         let mut temp_1 = [0.0; BLOCK_SIZE];
         let mut temp_2 = [0.0; BLOCK_SIZE];
 
-        let f0 = midi_to_freq(self.pitch) / 48000.0;
+        let f0 = midi_to_freq(self.pitch) / self.sample_rate;
         self.engine.render(
             false,
             self.trigger,
@@ -231,18 +341,21 @@ struct Synth<'a> {
     harmonics: f32,
     morph: f32,
     timbre: f32,
+    // semitones that make up for running at another rate than plaits expects
+    tuning: f32,
 }
 
 impl Synth<'_> {
-    fn new() -> Self {
+    fn new(sample_rate: f32) -> Self {
         Self {
             voice: Voice::new(&std::alloc::System, BLOCK_SIZE),
             patch: Patch::default(),
             modulations: Modulations::default(),
-            engine: 1,
+            engine: DEFAULT_ENGINE,
             morph: 0.5,
             harmonics: 0.5,
             timbre: 0.5,
+            tuning: 12.0 * (PLAITS_SAMPLE_RATE / sample_rate).log2(),
         }
     }
 
@@ -250,15 +363,23 @@ impl Synth<'_> {
         self.modulations.trigger_patched = true;
         self.modulations.level_patched = true;
         self.voice.init();
-        self.reset_params();
+        self.reset_params([false; PARAM_COUNT]);
     }
 
-    fn reset_params(&mut self) {
-        // reset params to saved settings (after changing them in sequence)
+    fn reset_params(&mut self, glide: [bool; PARAM_COUNT]) {
+        // reset params to saved settings (after changing them in sequence),
+        // glided ones carry on from where they are
         self.patch.engine = self.engine;
-        self.patch.harmonics = self.harmonics;
-        self.patch.timbre = self.timbre;
-        self.patch.morph = self.morph;
+        let [harmonics, timbre, morph] = glide;
+        if !harmonics {
+            self.patch.harmonics = self.harmonics;
+        }
+        if !timbre {
+            self.patch.timbre = self.timbre;
+        }
+        if !morph {
+            self.patch.morph = self.morph;
+        }
     }
 
     fn play(&mut self, pitch: i8, velocity: i8) {
@@ -270,7 +391,7 @@ impl Synth<'_> {
         self.tick();
 
         // note on
-        self.patch.note = pitch as f32;
+        self.patch.note = pitch as f32 + self.tuning;
         self.modulations.trigger = 1.0;
         self.modulations.level = velocity as f32 / 127.0;
     }
@@ -287,53 +408,76 @@ impl Synth<'_> {
     }
 }
 
+// a note the engine played, for tests and tools that follow the sequencer
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Trigger {
+    // samples since the engine started
+    pub sample: u64,
+    pub track: usize,
+    pub step: usize,
+    pub note: Note,
+}
+
 pub struct Engine<'a> {
     kick: Kick,
     snare: Snare,
     hihat: Hihat,
     channels: [Synth<'a>; SEQ_TRACK_COUNT],
+    // harmonics and timbre of the drums when a step doesn't lock them
+    drum_params: [[f32; 2]; DRUM_TRACK_COUNT],
+    glide: [[bool; PARAM_COUNT]; SEQ_TRACK_COUNT],
+    ramps: [[Ramp; PARAM_COUNT]; SEQ_TRACK_COUNT],
     tracks: [Track; SEQ_TRACK_COUNT],
-    limiter: Limiter,
-    time: f32,
-    prev_time: i8,
-    length: f32,
+    routes: [usize; SEQ_TRACK_COUNT],
+    // one per output pair, the first one also limits the full mix
+    limiters: [Limiter; SEQ_TRACK_COUNT],
+    // samples since the start of the pattern, counted rather than summed up
+    // from a fraction of a step so steps don't drift
+    position: u64,
+    sample: u64,
+    running: bool,
+    prev_step: Option<usize>,
+    step_length: f64,
+    length: usize,
+    sample_rate: f32,
+    triggers: Option<Sender<Trigger>>,
     pub ui_channel: (Sender<i8>, Receiver<i8>),
 }
 
 impl Engine<'_> {
-    pub fn new() -> Self {
+    pub fn new(sample_rate: f32) -> Self {
         Self {
-            kick: Kick::new(),
-            snare: Snare::new(),
-            hihat: Hihat::new(),
-            channels: [
-                Synth::new(),
-                Synth::new(),
-                Synth::new(),
-                Synth::new(),
-                Synth::new(),
-                Synth::new(),
-                Synth::new(),
-                Synth::new(),
-            ],
-            tracks: [
-                Track { notes: [None; 16] },
-                Track { notes: [None; 16] },
-                Track { notes: [None; 16] },
-                Track { notes: [None; 16] },
-                Track { notes: [None; 16] },
-                Track { notes: [None; 16] },
-                Track { notes: [None; 16] },
-                Track { notes: [None; 16] },
-            ],
-            limiter: Limiter::new(10.0, 500.0, 1.0),
-            time: 0.0,
-            prev_time: 0,
-            length: 16.0,
+            kick: Kick::new(sample_rate),
+            snare: Snare::new(sample_rate),
+            hihat: Hihat::new(sample_rate),
+            channels: std::array::from_fn(|_| Synth::new(sample_rate)),
+            drum_params: [[0.5; 2]; DRUM_TRACK_COUNT],
+            glide: [[false; PARAM_COUNT]; SEQ_TRACK_COUNT],
+            ramps: [[Ramp::default(); PARAM_COUNT]; SEQ_TRACK_COUNT],
+            tracks: std::array::from_fn(|_| Track {
+                notes: vec![None; INITIAL_STEP_COUNT],
+            }),
+            routes: [0; SEQ_TRACK_COUNT],
+            limiters: std::array::from_fn(|_| Limiter::new(10.0, 500.0, 1.0, sample_rate)),
+            position: 0,
+            sample: 0,
+            running: true,
+            prev_step: None,
+            step_length: Self::step_length(DEFAULT_BPM, sample_rate),
+            length: INITIAL_STEP_COUNT,
+            sample_rate,
+            triggers: None,
             ui_channel: crossbeam::channel::unbounded(),
         }
     }
 
+    // every note played from now on is reported on the returned channel
+    pub fn watch(&mut self) -> Receiver<Trigger> {
+        let (tx, rx) = crossbeam::channel::unbounded();
+        self.triggers = Some(tx);
+        rx
+    }
+
     pub fn init(&mut self) {
         for track in &mut self.channels {
             track.init()
@@ -342,59 +486,271 @@ impl Engine<'_> {
 
     #[inline]
     pub fn tick(&mut self) -> f32 {
-        self.increment_time();
+        let mut tracks = [0.0; SEQ_TRACK_COUNT];
+        self.tick_tracks(&mut tracks)
+    }
+
+    // like tick, also handing out the signal of every track on its own
+    #[inline]
+    pub fn tick_tracks(&mut self, tracks: &mut [f32; SEQ_TRACK_COUNT]) -> f32 {
+        self.render_tracks(tracks);
+        let mix = tracks[..MIX_TRACK_COUNT].iter().sum::<f32>() * TRACK_GAIN;
+        self.limiters[0].tick(mix)
+    }
+
+    // every output pair gets the tracks routed to it
+    #[inline]
+    pub fn tick_outputs(&mut self, outputs: &mut [f32]) {
+        let mut tracks = [0.0; SEQ_TRACK_COUNT];
+        self.render_tracks(&mut tracks);
+        route(&tracks, &self.routes, outputs);
+        for (out, limiter) in outputs.iter_mut().zip(self.limiters.iter_mut()) {
+            *out = limiter.tick(*out);
+        }
+    }
+
+    #[inline]
+    fn render_tracks(&mut self, tracks: &mut [f32; SEQ_TRACK_COUNT]) {
+        self.advance();
+        self.glide();
+        tracks[0] = self.kick.tick();
+        tracks[1] = self.snare.tick();
+        tracks[2] = self.hihat.tick();
+        for (out, synth) in tracks
+            .iter_mut()
+            .zip(self.channels.iter_mut())
+            .skip(DRUM_TRACK_COUNT)
+        {
+            *out = synth.tick();
+        }
+    }
 
-        if self.time as i8 != self.prev_time {
-            self.prev_time = self.time as i8;
-            self.ui_channel.0.send(self.time as i8).unwrap();
+    #[inline]
+    fn advance(&mut self) {
+        // a stopped sequencer lets the voices ring out
+        if !self.running {
+            self.sample += 1;
+            return;
         }
+        let mut step = (self.position as f64 / self.step_length) as usize;
+        if step >= self.length {
+            self.position = 0;
+            self.prev_step = None;
+            step = 0;
+        }
+        if self.prev_step != Some(step) {
+            self.prev_step = Some(step);
+            self.ui_channel.0.send(step as i8).unwrap();
+            self.trigger_step(step);
+        }
+        self.position += 1;
+        self.sample += 1;
+    }
 
+    // in samples, at the current tempo
+    pub fn pattern_length(&self) -> u64 {
+        (self.length as f64 * self.step_length).ceil() as u64
+    }
+
+    pub fn set_running(&mut self, running: bool) {
+        self.running = running;
+    }
+
+    // jump to a point given in samples from the start of the song, for
+    // following an external transport
+    pub fn locate(&mut self, sample: u64) {
+        self.position = sample % self.pattern_length().max(1);
+        // the step we land in only plays when landing right on its start
+        let steps = self.position as f64 / self.step_length;
+        self.prev_step = if steps.fract() * self.step_length < 1.0 {
+            None
+        } else {
+            Some(steps as usize)
+        };
+    }
+
+    fn trigger_step(&mut self, step: usize) {
         for track_idx in 0..SEQ_TRACK_COUNT {
-            for note_idx in 0..16 {
-                if note_idx as f32 != self.time {
-                    continue;
+            if let Some(&Some(note)) = self.tracks[track_idx].notes.get(step) {
+                if let Some(triggers) = &self.triggers {
+                    let _ = triggers.send(Trigger {
+                        sample: self.sample,
+                        track: track_idx,
+                        step,
+                        note,
+                    });
                 }
-                if let Some(note) = self.tracks[track_idx].notes[note_idx] {
-                    if track_idx == 0 {
-                        self.kick.p1 = note.parameters.harmonics.unwrap_or(0.5);
-                        self.kick.p2 = note.parameters.timbre.unwrap_or(0.5);
-                        self.kick.play(note.pitch, note.velocity);
-                    } else if track_idx == 1 {
-                        self.snare.p1 = note.parameters.harmonics.unwrap_or(0.5);
-                        self.snare.p2 = note.parameters.timbre.unwrap_or(0.5);
-                        self.snare.play(note.pitch, note.velocity);
-                    } else if track_idx == 2 {
-                        self.hihat.p1 = note.parameters.harmonics.unwrap_or(0.5);
-                        self.hihat.p2 = note.parameters.timbre.unwrap_or(0.5);
-                        self.hihat.play(note.pitch, note.velocity);
-                    } else {
-                        let t = &mut self.channels[track_idx];
-                        t.reset_params();
-                        t.play(note.pitch, note.velocity);
-                        note.parameters.engine.map(|v| t.patch.engine = v as usize);
-                        note.parameters.harmonics.map(|v| t.patch.harmonics = v);
-                        note.parameters.morph.map(|v| t.patch.morph = v);
-                        note.parameters.timbre.map(|v| t.patch.timbre = v);
+                if track_idx == 0 {
+                    let [p1, p2] = self.drum_params[0];
+                    let [glide1, glide2, _] = self.glide[0];
+                    if !glide1 || note.parameters.harmonics.is_some() {
+                        self.kick.p1 = note.parameters.harmonics.unwrap_or(p1);
+                    }
+                    if !glide2 || note.parameters.timbre.is_some() {
+                        self.kick.p2 = note.parameters.timbre.unwrap_or(p2);
+                    }
+                    self.kick.play(note.pitch, note.velocity);
+                } else if track_idx == 1 {
+                    let [p1, p2] = self.drum_params[1];
+                    let [glide1, glide2, _] = self.glide[1];
+                    if !glide1 || note.parameters.harmonics.is_some() {
+                        self.snare.p1 = note.parameters.harmonics.unwrap_or(p1);
+                    }
+                    if !glide2 || note.parameters.timbre.is_some() {
+                        self.snare.p2 = note.parameters.timbre.unwrap_or(p2);
+                    }
+                    self.snare.play(note.pitch, note.velocity);
+                } else if track_idx == 2 {
+                    let [p1, p2] = self.drum_params[2];
+                    let [glide1, glide2, _] = self.glide[2];
+                    if !glide1 || note.parameters.harmonics.is_some() {
+                        self.hihat.p1 = note.parameters.harmonics.unwrap_or(p1);
+                    }
+                    if !glide2 || note.parameters.timbre.is_some() {
+                        self.hihat.p2 = note.parameters.timbre.unwrap_or(p2);
+                    }
+                    self.hihat.play(note.pitch, note.velocity);
+                } else {
+                    let t = &mut self.channels[track_idx];
+                    t.reset_params(self.glide[track_idx]);
+                    t.play(note.pitch, note.velocity);
+                    note.parameters.engine.map(|v| t.patch.engine = v as usize);
+                    note.parameters.harmonics.map(|v| t.patch.harmonics = v);
+                    note.parameters.morph.map(|v| t.patch.morph = v);
+                    note.parameters.timbre.map(|v| t.patch.timbre = v);
+                }
+                self.start_glides(track_idx, step, &note.parameters);
+            }
+        }
+    }
+
+    // glided parameters head from the value locked here to the next one
+    // locked on the track, arriving right on its step
+    fn start_glides(&mut self, track: usize, step: usize, params: &Params) {
+        for (idx, &param) in PARAMS.iter().enumerate() {
+            let Some(from) = params.get(param).filter(|_| self.glide[track][idx]) else {
+                continue;
+            };
+            let notes = &self.tracks[track].notes;
+            let next = (1..=self.length).find_map(|distance| {
+                let note = notes.get((step + distance) % self.length)?.as_ref()?;
+                Some((distance, note.parameters.get(param)?))
+            });
+            self.ramps[track][idx] = match next {
+                Some((distance, to)) if distance < self.length => {
+                    let samples = ((distance as f64 * self.step_length) as u64).max(1);
+                    Ramp {
+                        value: from,
+                        delta: (to - from) / samples as f32,
+                        samples,
                     }
                 }
+                // a value locked on a single step just stays
+                _ => Ramp::default(),
+            };
+        }
+    }
+
+    #[inline]
+    fn glide(&mut self) {
+        for track in 0..SEQ_TRACK_COUNT {
+            for (idx, &param) in PARAMS.iter().enumerate() {
+                let Some(value) = self.ramps[track][idx].tick() else {
+                    continue;
+                };
+                if let Some(live) = self.live(track, param) {
+                    *live = value;
+                }
             }
         }
-        // TODO: render and mix all tracks
-        // let mut mix = self.tracks.iter().reduce(|a, b| a + b);
-        let mult = 1.0 / 3.0;
-        let mut mix = self.kick.tick() * mult;
-        mix += self.snare.tick() * mult;
-        mix += self.hihat.tick() * mult;
+    }
 
-        mix = self.limiter.tick(mix);
+    // the value a track's voice plays with right now
+    fn live(&mut self, track: usize, param: Param) -> Option<&mut f32> {
+        let (p1, p2) = match track {
+            0 => (&mut self.kick.p1, &mut self.kick.p2),
+            1 => (&mut self.snare.p1, &mut self.snare.p2),
+            2 => (&mut self.hihat.p1, &mut self.hihat.p2),
+            _ => {
+                let patch = &mut self.channels.get_mut(track)?.patch;
+                return Some(match param {
+                    Param::Harmonics => &mut patch.harmonics,
+                    Param::Timbre => &mut patch.timbre,
+                    Param::Morph => &mut patch.morph,
+                });
+            }
+        };
+        match param {
+            Param::Harmonics => Some(p1),
+            Param::Timbre => Some(p2),
+            Param::Morph => None,
+        }
+    }
 
-        mix
+    // tweaks are heard right away on the playing note too
+    fn set_param(&mut self, track: usize, param: Param, value: f32) {
+        if track < DRUM_TRACK_COUNT {
+            match param {
+                Param::Harmonics => self.drum_params[track][0] = value,
+                Param::Timbre => self.drum_params[track][1] = value,
+                Param::Morph => {}
+            }
+        } else if let Some(synth) = self.channels.get_mut(track) {
+            match param {
+                Param::Harmonics => synth.harmonics = value,
+                Param::Timbre => synth.timbre = value,
+                Param::Morph => synth.morph = value,
+            }
+        }
+        if let Some(live) = self.live(track, param) {
+            *live = value;
+        }
     }
 
     pub fn set_state(&mut self, state: State) {
+        // a shorter pattern wraps around on the next tick
+        self.length = state[0].notes.len();
         self.tracks = state;
     }
 
+    pub fn control(&mut self, control: Control) {
+        match control {
+            Control::Bpm(bpm) => {
+                // stay at the same point within the current step
+                let steps = self.position as f64 / self.step_length;
+                self.step_length = Self::step_length(bpm, self.sample_rate);
+                self.position = (steps * self.step_length).ceil() as u64;
+            }
+            Control::Engine { track, engine } => {
+                if let Some(synth) = self.channels.get_mut(track) {
+                    synth.engine = engine;
+                }
+            }
+            Control::Route { track, output } => {
+                if let Some(route) = self.routes.get_mut(track) {
+                    *route = output;
+                }
+            }
+            Control::Param {
+                track,
+                param,
+                value,
+            } => self.set_param(track, param, value),
+            Control::Glide { track, param, on } => {
+                if track < SEQ_TRACK_COUNT {
+                    let idx = param as usize;
+                    self.glide[track][idx] = on;
+                    self.ramps[track][idx] = Ramp::default();
+                }
+            }
+        }
+    }
+
+    fn step_length(bpm: f32, sample_rate: f32) -> f64 {
+        // samples per step
+        60.0 * sample_rate as f64 / (bpm * STEPS_PER_BEAT) as f64
+    }
+
     pub fn clear_track(&mut self, track_index: usize) {
         // self.seq.clear_track(track_index);
     }
@@ -405,11 +761,74 @@ impl Engine<'_> {
             self.clear_track(i);
         }
     }
+}
 
-    fn increment_time(&mut self) {
-        self.time += 1.0 / 16384.0;
-        if self.time >= self.length {
-            self.time = 0.0;
+// sums the tracks into their output pairs, tracks routed past the last pair
+// the device has play on the first one
+fn route(tracks: &[f32; SEQ_TRACK_COUNT], routes: &[usize; SEQ_TRACK_COUNT], outputs: &mut [f32]) {
+    outputs.fill(0.0);
+    for (&track, &output) in tracks.iter().zip(routes).take(MIX_TRACK_COUNT) {
+        let output = if output < outputs.len() { output } else { 0 };
+        outputs[output] += track * TRACK_GAIN;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route() {
+        // the snare is routed past the outputs and falls back to the first,
+        // the synth tracks aren't mixed
+        let tracks = [3.0, 3.0, 3.0, 6.0, 0.0, 0.0, 0.0, 9.0];
+        let mut outputs = [0.0; 3];
+        route(&tracks, &[0, 5, 1, 1, 2, 2, 2, 2], &mut outputs);
+        for (output, expected) in outputs.iter().zip([2.0, 1.0, 0.0]) {
+            assert!((output - expected).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_glide() {
+        let mut engine = Engine::new(48000.0);
+        engine.init();
+        let mut state: State = std::array::from_fn(|_| Track {
+            notes: vec![None; 8],
+        });
+        for (step, timbre) in [(0, 0.2), (4, 0.6)] {
+            let mut note = Note::new(step as f32, 60, 100);
+            note.parameters.timbre = Some(timbre);
+            state[3].notes[step] = Some(note);
+        }
+        engine.set_state(state);
+        let step = Engine::step_length(DEFAULT_BPM, 48000.0) as usize;
+        let timbre = |engine: &Engine| engine.channels[3].patch.timbre;
+
+        // stepwise, the first lock holds until the next one
+        for _ in 0..2 * step {
+            engine.tick();
+        }
+        assert_eq!(timbre(&engine), 0.2);
+
+        // halfway there, then right on the next lock and back towards the first
+        engine.control(Control::Glide {
+            track: 3,
+            param: Param::Timbre,
+            on: true,
+        });
+        engine.locate(0);
+        for _ in 0..2 * step {
+            engine.tick();
+        }
+        assert!((timbre(&engine) - 0.4).abs() < 1e-3);
+        for _ in 0..2 * step + 1 {
+            engine.tick();
+        }
+        assert!((timbre(&engine) - 0.6).abs() < 1e-3);
+        for _ in 0..2 * step {
+            engine.tick();
         }
+        assert!((timbre(&engine) - 0.4).abs() < 1e-3);
     }
 }
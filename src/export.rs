@@ -0,0 +1,180 @@
+use anyhow::{bail, Context, Result};
+use hound::{SampleFormat, WavSpec, WavWriter};
+use std::{
+    fs::{self, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+
+use crate::engine::{Engine, SEQ_TRACK_COUNT, TRACK_GAIN, TRACK_NAMES};
+
+// how long the voices may ring out after the pattern, in seconds
+const MAX_TAIL: f32 = 10.0;
+// a tail ends once every track stayed below this for a while
+const SILENCE: f32 = 1.0e-4;
+const SILENCE_LENGTH: f32 = 0.1;
+// how long the loops may play, in seconds, so a typo in the number of loops
+// doesn't fill the disk
+pub const MAX_LENGTH: u64 = 30 * 60;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Fader {
+    // the track as the voice plays it
+    Pre,
+    // the track at the level it has in the mix. the master goes through the
+    // limiter and the stems don't, so they only add up to it while the
+    // limiter is idle
+    Post,
+}
+
+impl Fader {
+    pub fn from_name(name: &str) -> Result<Fader> {
+        match name {
+            "pre" => Ok(Fader::Pre),
+            "post" => Ok(Fader::Post),
+            _ => bail!("stems are either pre or post fader: {}", name),
+        }
+    }
+}
+
+// the master and every track on its own, all of the same length so they line
+// up when dropped into a daw. the synth tracks get stems but aren't in the
+// master, just like they aren't in the mix
+pub struct Stems {
+    sample_rate: u32,
+    files: Vec<PathBuf>,
+    frames: u64,
+}
+
+impl Stems {
+    // plays the pattern `loops` times from the start, then stops the
+    // sequencer and lets the voices ring out until every track is silent.
+    // the samples go straight to master.wav and a file per track named like
+    // 01-kick.wav, only the tail that may turn out to be silence is held back
+    pub fn render(
+        mut engine: Engine,
+        sample_rate: u32,
+        loops: usize,
+        fader: Fader,
+        dir: &Path,
+    ) -> Result<Stems> {
+        fs::create_dir_all(dir).with_context(|| format!("can't create {}", dir.display()))?;
+        let mut files = vec![dir.join("master.wav")];
+        for (track, name) in TRACK_NAMES.iter().enumerate() {
+            files.push(dir.join(format!("{:02}-{}.wav", track + 1, name)));
+        }
+        let mut writers = files
+            .iter()
+            .map(|path| create_wav(path, sample_rate))
+            .collect::<Result<Vec<Writer>>>()?;
+
+        let pattern = engine.pattern_length() * loops as u64;
+        let max_tail = (MAX_TAIL * sample_rate as f32) as u64;
+        let silence = (SILENCE_LENGTH * sample_rate as f32) as usize;
+        let mut tracks = [0.0; SEQ_TRACK_COUNT];
+        // frames since the last one that wasn't silent
+        let mut pending: Vec<[f32; SEQ_TRACK_COUNT + 1]> = vec![];
+        let mut frames = 0;
+
+        engine.locate(0);
+        engine.set_running(true);
+        for sample in 0..pattern + max_tail {
+            if sample == pattern {
+                engine.set_running(false);
+            }
+            let mut frame = [0.0; SEQ_TRACK_COUNT + 1];
+            frame[0] = engine.tick_tracks(&mut tracks);
+            for (out, &track) in frame[1..].iter_mut().zip(&tracks) {
+                *out = match fader {
+                    Fader::Pre => track,
+                    Fader::Post => track * TRACK_GAIN,
+                };
+            }
+            pending.push(frame);
+
+            // every file ends with the last track to fall silent
+            let loud = frame[0].abs() >= SILENCE || tracks.iter().any(|out| out.abs() >= SILENCE);
+            if sample < pattern || loud {
+                for frame in pending.drain(..) {
+                    write_frame(&mut writers, &frame)?;
+                    frames += 1;
+                }
+            } else if pending.len() > silence {
+                break;
+            }
+        }
+
+        for (writer, path) in writers.into_iter().zip(&files) {
+            writer
+                .finalize()
+                .with_context(|| format!("can't write {}", path.display()))?;
+        }
+        Ok(Stems {
+            sample_rate,
+            files,
+            frames,
+        })
+    }
+
+    // at least one loop, however long the pattern
+    pub fn max_loops(engine: &Engine, sample_rate: u32) -> usize {
+        let pattern = engine.pattern_length().max(1);
+        (MAX_LENGTH * sample_rate as u64 / pattern).max(1) as usize
+    }
+
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.frames as f32 / self.sample_rate as f32
+    }
+}
+
+type Writer = WavWriter<BufWriter<File>>;
+
+fn create_wav(path: &Path, sample_rate: u32) -> Result<Writer> {
+    // the engine is mono, floats keep whatever the limiter let through
+    let spec = WavSpec {
+        channels: 1,
+        sample_rate,
+        bits_per_sample: 32,
+        sample_format: SampleFormat::Float,
+    };
+    WavWriter::create(path, spec).with_context(|| format!("can't write {}", path.display()))
+}
+
+fn write_frame(writers: &mut [Writer], frame: &[f32]) -> Result<()> {
+    for (writer, &sample) in writers.iter_mut().zip(frame) {
+        writer.write_sample(sample)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let mut engine = Engine::new(48000.0);
+        engine.init();
+        let pattern = engine.pattern_length() as usize;
+        let max_loops = Stems::max_loops(&engine, 48000);
+        assert!(max_loops * pattern <= MAX_LENGTH as usize * 48000);
+        assert!((max_loops + 1) * pattern > MAX_LENGTH as usize * 48000);
+
+        let dir = std::env::temp_dir().join(format!("bl8-stems-{}", std::process::id()));
+        let stems = Stems::render(engine, 48000, 2, Fader::Post, &dir).unwrap();
+        let files = stems.files();
+        assert_eq!(files.len(), SEQ_TRACK_COUNT + 1);
+        assert!(files[1].ends_with("01-kick.wav"));
+        assert!(stems.frames >= 2 * pattern as u64);
+        for file in files {
+            let reader = hound::WavReader::open(file).unwrap();
+            assert_eq!(reader.spec().sample_rate, 48000);
+            assert_eq!(reader.len() as u64, stems.frames);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
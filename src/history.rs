@@ -1,234 +1,824 @@
-use crate::engine::{Note, Params, State, Track, INITIAL_STEP_COUNT, SEQ_TRACK_COUNT};
+use crate::cell::{scale, Cell, CellKind, Fx, MAX_VALUE};
+use crate::engine::{Note, State, Track, INITIAL_STEP_COUNT, MAX_STEP_COUNT, SEQ_TRACK_COUNT};
+use crate::project::{grid_from_lines, grid_to_text};
+use anyhow::{anyhow, bail, Context};
 use crossbeam::channel::*;
-use regex::Regex;
-use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-pub const PITCHES: [&str; 11] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "B"];
-pub type Grid = Vec<Vec<String>>;
+// notes without a velocity command
+const DEFAULT_VELOCITY: i8 = 100;
+pub type Grid = Vec<Vec<Cell>>;
+
+const UNDO_HEADER: &str = "# bl8 undo";
+// like vim's undolevels, older revisions are forgotten once there are more
+// than this, keeping memory bounded however long a session runs
+pub const MAX_REVISIONS: usize = 10_000;
+
+// an edit to the grid, history stores these together with their inverses
+// instead of copies of the grid
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Insert { x: usize, y: usize, cell: Cell },
+    Delete { x: usize, y: usize },
+    Resize { len: usize },
+}
+
+// a node in the undo tree, revisions are numbered in the order they were
+// created and revision 0 is the grid history started from
+struct Revision {
+    parent: Option<usize>,
+    // the child that redo moves to, i.e. the most recently visited branch
+    redo: Option<usize>,
+    time: SystemTime,
+    // changes lead from the parent to this revision, inverse leads back
+    changes: Vec<Command>,
+    inverse: Vec<Command>,
+}
 
 pub struct History {
-    history: Vec<Grid>,
+    grid: Grid,
+    revisions: Vec<Revision>,
     pos: usize,
+    // nesting depth of begin/commit, and the revision the open group writes to
+    depth: usize,
+    group: Option<usize>,
     pub channel: (Sender<State>, Receiver<State>),
 }
 
 impl History {
     pub fn new() -> History {
         History {
-            history: vec![vec![
-                vec!["___ ".to_string(); INITIAL_STEP_COUNT];
-                SEQ_TRACK_COUNT * 3
-            ]],
+            grid: vec![vec![Cell::Empty; INITIAL_STEP_COUNT]; SEQ_TRACK_COUNT * 3],
+            revisions: vec![Self::root()],
             pos: 0,
+            depth: 0,
+            group: None,
             channel: crossbeam::channel::unbounded(),
         }
     }
 
+    fn root() -> Revision {
+        Revision {
+            parent: None,
+            redo: None,
+            time: SystemTime::now(),
+            changes: vec![],
+            inverse: vec![],
+        }
+    }
+
+    pub fn reset(&mut self, grid: Grid) {
+        // start over from a freshly loaded grid
+        self.grid = grid;
+        self.revisions = vec![Self::root()];
+        self.pos = 0;
+        self.group = None;
+        self.send_state();
+    }
+
     pub fn get_grid(&self) -> &Grid {
-        &self.history[self.pos]
+        &self.grid
     }
 
-    pub fn push(&mut self, grid: Grid) {
-        let state = Self::to_state(grid.clone());
-        self.channel.0.send(state).unwrap();
-        self.history.truncate(self.pos + 1);
-        self.history.push(grid);
-        self.pos += 1;
+    // everything applied between begin and commit is undone as one change,
+    // groups can be nested and only the outermost commit closes them
+    pub fn begin(&mut self) {
+        self.depth += 1;
     }
 
-    pub fn undo(&mut self) {
-        if self.pos > 0 {
-            self.pos -= 1;
+    pub fn commit(&mut self) {
+        self.depth = self.depth.saturating_sub(1);
+        if self.depth == 0 {
+            self.group = None;
         }
+    }
 
-        let grid = self.history[self.pos].clone();
-        let state = Self::to_state(grid.clone());
-        self.channel.0.send(state).unwrap();
+    pub fn apply(&mut self, cmds: Vec<Command>) {
+        self.apply_at(cmds, SystemTime::now());
+    }
+
+    fn apply_at(&mut self, cmds: Vec<Command>, time: SystemTime) {
+        // commands that don't change anything don't get a revision, each one
+        // is checked against the grid the commands before it left behind
+        let mut changes = vec![];
+        let mut inverse = vec![];
+        for cmd in cmds {
+            if Self::is_noop(&self.grid, &cmd) {
+                continue;
+            }
+            let mut undo = Self::execute(&mut self.grid, &cmd);
+            undo.extend(inverse);
+            inverse = undo;
+            changes.push(cmd);
+        }
+        if changes.is_empty() {
+            return;
+        }
+        let cmds = changes;
+
+        if self.group == Some(self.pos) {
+            let revision = &mut self.revisions[self.pos];
+            revision.changes.extend(cmds);
+            inverse.append(&mut revision.inverse);
+            revision.inverse = inverse;
+        } else {
+            if self.revisions.len() >= MAX_REVISIONS {
+                self.prune(MAX_REVISIONS / 2);
+            }
+            // new changes start a new branch instead of dropping the redo states
+            let idx = self.revisions.len();
+            self.revisions.push(Revision {
+                parent: Some(self.pos),
+                redo: None,
+                time,
+                changes: cmds,
+                inverse,
+            });
+            self.revisions[self.pos].redo = Some(idx);
+            self.pos = idx;
+            if self.depth > 0 {
+                self.group = Some(idx);
+            }
+        }
+        self.send_state();
+    }
+
+    // make the oldest ancestor of the current revision that has at most
+    // `keep` revisions below it the new root, dropping everything else
+    fn prune(&mut self, keep: usize) {
+        // children are always created after their parent, so sizes can be
+        // summed up backwards
+        let mut sizes = vec![1; self.revisions.len()];
+        for idx in (1..self.revisions.len()).rev() {
+            if let Some(parent) = self.revisions[idx].parent {
+                sizes[parent] += sizes[idx];
+            }
+        }
+        let mut root = self.pos;
+        while let Some(parent) = self.revisions[root].parent {
+            if sizes[parent] > keep {
+                break;
+            }
+            root = parent;
+        }
+        if root == 0 {
+            return;
+        }
+
+        let mut index = vec![None; self.revisions.len()];
+        index[root] = Some(0);
+        let mut next = 1;
+        for idx in root + 1..self.revisions.len() {
+            if self.revisions[idx]
+                .parent
+                .is_some_and(|parent| index[parent].is_some())
+            {
+                index[idx] = Some(next);
+                next += 1;
+            }
+        }
+        let revisions = std::mem::take(&mut self.revisions);
+        self.revisions = revisions
+            .into_iter()
+            .enumerate()
+            .filter(|(idx, _)| index[*idx].is_some())
+            .map(|(_, mut revision)| {
+                revision.parent = revision.parent.and_then(|parent| index[parent]);
+                revision.redo = revision.redo.and_then(|redo| index[redo]);
+                revision
+            })
+            .collect();
+        // the new root is where undo stops, so it has nothing to undo
+        self.revisions[0].changes.clear();
+        self.revisions[0].inverse.clear();
+        self.pos = index[self.pos].unwrap();
+        self.group = None;
+    }
+
+    // cells outside the grid can't change, so they count as no-ops too
+    fn is_noop(grid: &Grid, cmd: &Command) -> bool {
+        let cell = |x: usize, y: usize| grid.get(x).and_then(|column| column.get(y));
+        match cmd {
+            Command::Insert { x, y, cell: new } => cell(*x, *y).is_none_or(|cell| cell == new),
+            Command::Delete { x, y } => cell(*x, *y).is_none_or(|cell| cell.is_empty()),
+            Command::Resize { len } => grid[0].len() == *len,
+        }
+    }
+
+    // apply a command and return the commands that undo it
+    fn execute(grid: &mut Grid, cmd: &Command) -> Vec<Command> {
+        let restore = |x: usize, y: usize, cell: Cell| match cell {
+            Cell::Empty => Command::Delete { x, y },
+            cell => Command::Insert { x, y, cell },
+        };
+        match *cmd {
+            Command::Insert { x, y, cell } => {
+                let prev = std::mem::replace(&mut grid[x][y], cell);
+                vec![restore(x, y, prev)]
+            }
+            Command::Delete { x, y } => {
+                let prev = std::mem::replace(&mut grid[x][y], Cell::Empty);
+                vec![restore(x, y, prev)]
+            }
+            Command::Resize { len } => {
+                let mut inverse = vec![Command::Resize { len: grid[0].len() }];
+                for (x, column) in grid.iter_mut().enumerate() {
+                    let removed = column.split_off(len.min(column.len()));
+                    for (y, cell) in removed.into_iter().enumerate() {
+                        if !cell.is_empty() {
+                            inverse.push(restore(x, len + y, cell));
+                        }
+                    }
+                    column.resize(len, Cell::Empty);
+                }
+                inverse
+            }
+        }
+    }
+
+    pub fn undo(&mut self) {
+        self.group = None;
+        if let Some(parent) = self.revisions[self.pos].parent {
+            self.revisions[parent].redo = Some(self.pos);
+            self.step_up();
+        }
+        self.send_state();
     }
 
     pub fn redo(&mut self) {
-        if self.pos < self.history.len() - 1 {
-            self.pos += 1;
+        self.group = None;
+        if let Some(child) = self.revisions[self.pos].redo {
+            self.step_down(child);
         }
+        self.send_state();
+    }
+
+    fn step_up(&mut self) {
+        let revision = &self.revisions[self.pos];
+        for cmd in &revision.inverse {
+            Self::execute(&mut self.grid, cmd);
+        }
+        self.pos = revision.parent.unwrap_or(self.pos);
+    }
+
+    fn step_down(&mut self, child: usize) {
+        for cmd in &self.revisions[child].changes {
+            Self::execute(&mut self.grid, cmd);
+        }
+        self.pos = child;
+    }
+
+    // g- and g+ move through revisions in the order they were made, across
+    // branches
+    pub fn earlier(&mut self, count: usize) {
+        self.goto(self.pos.saturating_sub(count));
+    }
+
+    pub fn later(&mut self, count: usize) {
+        self.goto(self.pos.saturating_add(count).min(self.revisions.len() - 1));
+    }
 
-        let grid = self.history[self.pos].clone();
-        let state = Self::to_state(grid.clone());
+    pub fn earlier_by(&mut self, duration: Duration) {
+        let time = self.revisions[self.pos].time;
+        let target = time.checked_sub(duration).unwrap_or(UNIX_EPOCH);
+        let idx = self.revisions[..=self.pos]
+            .iter()
+            .rposition(|revision| revision.time <= target)
+            .unwrap_or(0);
+        self.goto(idx);
+    }
+
+    pub fn later_by(&mut self, duration: Duration) {
+        let target = self.revisions[self.pos].time.checked_add(duration);
+        let idx = self.revisions[self.pos..]
+            .iter()
+            .rposition(|revision| target.is_none_or(|target| revision.time <= target))
+            .map_or(self.pos, |idx| self.pos + idx);
+        self.goto(idx);
+    }
+
+    pub fn revision(&self) -> (usize, SystemTime) {
+        (self.pos, self.revisions[self.pos].time)
+    }
+
+    fn goto(&mut self, idx: usize) {
+        self.group = None;
+        // point redo along the path from the root, so undo and redo stay on
+        // the branch we jumped to
+        let mut path = vec![idx];
+        while let Some(parent) = self.revisions[path[path.len() - 1]].parent {
+            self.revisions[parent].redo = Some(path[path.len() - 1]);
+            path.push(parent);
+        }
+        // undo up to the common ancestor, then redo down to the target
+        while !path.contains(&self.pos) {
+            self.step_up();
+        }
+        let ancestor = path.iter().position(|&idx| idx == self.pos).unwrap();
+        for &child in path[..ancestor].iter().rev() {
+            self.step_down(child);
+        }
+        self.send_state();
+    }
+
+    fn send_state(&self) {
+        let state = Self::to_state(self.get_grid());
         self.channel.0.send(state).unwrap();
     }
-    pub fn to_state(grid: Grid) -> [Track; 8] {
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        // the current grid is stored so the file can be checked against the
+        // project it belongs to, revisions only hold their changes
+        let mut text = format!("{}\ncurrent {}\n\n", UNDO_HEADER, self.pos);
+        text.push_str(&grid_to_text(&self.grid));
+        for revision in &self.revisions {
+            let millis = revision
+                .time
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis();
+            let field = |idx: Option<usize>| idx.map_or("-".to_string(), |idx| idx.to_string());
+            text.push_str(&format!(
+                "\nrevision {} {} {}\n",
+                field(revision.parent),
+                field(revision.redo),
+                millis
+            ));
+            for cmd in &revision.changes {
+                text.push_str(&format!("+ {}\n", Self::format_command(cmd)));
+            }
+            for cmd in &revision.inverse {
+                text.push_str(&format!("- {}\n", Self::format_command(cmd)));
+            }
+        }
+        fs::write(path, text).with_context(|| format!("can't write {}", path.display()))
+    }
+
+    pub fn load(&mut self, path: &Path) -> anyhow::Result<()> {
+        // the undo tree only applies if it ends in the grid that was loaded
+        let text =
+            fs::read_to_string(path).with_context(|| format!("can't read {}", path.display()))?;
+        let (pos, grid, revisions) =
+            Self::parse_undo(&text).with_context(|| format!("can't load {}", path.display()))?;
+        if grid != self.grid {
+            bail!("{} doesn't match the project", path.display());
+        }
+        self.revisions = revisions;
+        self.pos = pos;
+        self.group = None;
+        Ok(())
+    }
+
+    fn format_command(cmd: &Command) -> String {
+        match cmd {
+            Command::Insert { x, y, cell } => format!("insert {} {} {}", x, y, cell),
+            Command::Delete { x, y } => format!("delete {} {}", x, y),
+            Command::Resize { len } => format!("resize {}", len),
+        }
+    }
+
+    fn parse_command(line: &str) -> anyhow::Result<Command> {
+        let fields = line.split(' ').collect::<Vec<&str>>();
+        // anything outside the largest grid would panic once undone or redone
+        let number = |idx: usize, range: std::ops::Range<usize>| {
+            fields
+                .get(idx)
+                .and_then(|field| field.parse::<usize>().ok())
+                .filter(|number| range.contains(number))
+                .ok_or_else(|| anyhow!("invalid command: {}", line))
+        };
+        let (x, y) = (0..SEQ_TRACK_COUNT * 3, 0..MAX_STEP_COUNT);
+        match (fields[0], fields.len()) {
+            ("insert", 4) => Ok(Command::Insert {
+                x: number(1, x.clone())?,
+                y: number(2, y)?,
+                cell: Cell::parse(fields[3], CellKind::of(number(1, x)?))?,
+            }),
+            ("delete", 3) => Ok(Command::Delete {
+                x: number(1, x)?,
+                y: number(2, y)?,
+            }),
+            ("resize", 2) => Ok(Command::Resize {
+                len: number(1, 1..MAX_STEP_COUNT + 1)?,
+            }),
+            _ => bail!("invalid command: {}", line),
+        }
+    }
+
+    fn parse_undo(text: &str) -> anyhow::Result<(usize, Grid, Vec<Revision>)> {
+        let mut lines = text.lines().peekable();
+        if lines.next() != Some(UNDO_HEADER) {
+            bail!("not a bl8 undo file");
+        }
+        let pos = lines
+            .next()
+            .and_then(|line| line.strip_prefix("current "))
+            .and_then(|pos| pos.parse::<usize>().ok())
+            .ok_or_else(|| anyhow!("missing current revision"))?;
+        let grid = grid_from_lines(
+            std::iter::from_fn(|| lines.next_if(|line| !line.starts_with("revision ")))
+                .filter(|line| !line.trim().is_empty()),
+        )?;
+
+        let mut revisions = vec![];
+        while let Some(line) = lines.next() {
+            let fields = line
+                .strip_prefix("revision ")
+                .map(|line| line.split_whitespace().collect::<Vec<&str>>())
+                .unwrap_or_default();
+            let [parent, redo, millis] = fields.as_slice() else {
+                bail!("invalid revision: {}", line);
+            };
+            let index = |field: &str| match field {
+                "-" => Ok(None),
+                field => field
+                    .parse::<usize>()
+                    .map(Some)
+                    .map_err(|_| anyhow!("invalid revision: {}", field)),
+            };
+            let millis = millis
+                .parse::<u64>()
+                .map_err(|_| anyhow!("invalid time: {}", millis))?;
+            let mut revision = Revision {
+                parent: index(parent)?,
+                redo: index(redo)?,
+                time: UNIX_EPOCH + Duration::from_millis(millis),
+                changes: vec![],
+                inverse: vec![],
+            };
+            while let Some(line) = lines.next_if(|line| !line.starts_with("revision ")) {
+                if let Some(cmd) = line.strip_prefix("+ ") {
+                    revision.changes.push(Self::parse_command(cmd)?);
+                } else if let Some(cmd) = line.strip_prefix("- ") {
+                    revision.inverse.push(Self::parse_command(cmd)?);
+                } else if !line.trim().is_empty() {
+                    bail!("invalid change: {}", line);
+                }
+            }
+            revisions.push(revision);
+        }
+
+        // parents come before their children and only the root has none, so
+        // walking up always ends, and redo points at one of the children
+        if pos >= revisions.len() {
+            bail!("revision out of range");
+        }
+        for (idx, revision) in revisions.iter().enumerate() {
+            let parent_valid = match revision.parent {
+                None => idx == 0,
+                Some(parent) => parent < idx,
+            };
+            let redo_valid = revision.redo.is_none_or(|redo| {
+                revisions
+                    .get(redo)
+                    .is_some_and(|child| child.parent == Some(idx))
+            });
+            if !parent_valid || !redo_valid {
+                bail!("invalid revision: {}", idx);
+            }
+        }
+
+        // replay the tree from the current grid, every command has to fit the
+        // pattern as long as it is at that point and undoing a revision has to
+        // bring back its parent's length. only the length matters as it's all
+        // that undo and redo could run past
+        let mut root = grid[0].len();
+        let mut idx = pos;
+        while let Some(parent) = revisions[idx].parent {
+            root = Self::replay_length(root, &revisions[idx].inverse)
+                .with_context(|| format!("revision {}", idx))?;
+            idx = parent;
+        }
+        let mut lengths = vec![root];
+        for (idx, revision) in revisions.iter().enumerate().skip(1) {
+            let parent = lengths[revision.parent.unwrap()];
+            let check = || -> anyhow::Result<usize> {
+                let len = Self::replay_length(parent, &revision.changes)?;
+                if Self::replay_length(len, &revision.inverse)? != parent {
+                    bail!("undoing doesn't restore the length");
+                }
+                Ok(len)
+            };
+            lengths.push(check().with_context(|| format!("revision {}", idx))?);
+        }
+        Ok((pos, grid, revisions))
+    }
+
+    // the pattern length after the commands, which have to fit it as it
+    // changes
+    fn replay_length(mut len: usize, cmds: &[Command]) -> anyhow::Result<usize> {
+        for cmd in cmds {
+            match *cmd {
+                Command::Insert { y, .. } | Command::Delete { y, .. } if y >= len => {
+                    bail!("step {} is outside the pattern", y);
+                }
+                Command::Resize { len: new } => len = new,
+                _ => {}
+            }
+        }
+        Ok(len)
+    }
+
+    pub fn to_state(grid: &Grid) -> [Track; 8] {
         grid.chunks(3)
             .map(|g| Track {
-                notes: g[0]
-                    .iter()
-                    .enumerate()
-                    .map(|(step, _)| {
-                        let cells =
-                            vec![g[0][step].clone(), g[1][step].clone(), g[2][step].clone()];
-                        History::parse_input(&cells, step)
-                    })
-                    .collect::<Vec<Option<Note>>>()
-                    .try_into()
-                    .unwrap(),
+                notes: (0..g[0].len())
+                    .map(|step| Self::to_note([g[0][step], g[1][step], g[2][step]], step))
+                    .collect::<Vec<Option<Note>>>(),
             })
             .collect::<Vec<Track>>()
             .try_into()
             .unwrap()
     }
 
-    fn parse_input(input: &Vec<String>, note_index: usize) -> Option<Note> {
-        let re = Regex::new(r"\d").unwrap();
-        if let Some(idx) = Self::parse_pitch(input[0].as_str()) {
-            return Some(Note {
-                timestamp: note_index as f32,
-                pitch: idx as i8,
-                velocity: 100,
-                parameters: {
-                    let mut params = Params::new();
-                    if let Ok(harmonics) = input[1].parse::<i8>() {
-                        params.harmonics = Some(harmonics as f32 / 100.0);
-                    }
-                    if let Ok(timbre) = input[2].parse::<i8>() {
-                        params.timbre = Some(timbre as f32 / 100.0);
-                    }
-                    params
-                },
-            });
-        } else if re.is_match(&input[0]) {
-            if let Ok(pitch) = input[0].parse::<i8>() {
-                Some(Note {
-                    timestamp: note_index as f32,
-                    pitch,
-                    velocity: 100,
-                    parameters: {
-                        let mut params = Params::new();
-                        if let Ok(harmonics) = input[1].parse::<i8>() {
-                            params.harmonics = Some(harmonics as f32 / 100.0);
-                        }
-                        if let Ok(timbre) = input[2].parse::<i8>() {
-                            params.timbre = Some(timbre as f32 / 100.0);
-                        }
-                        params
-                    },
-                })
-            } else {
-                None
+    fn to_note(cells: [Cell; 3], step: usize) -> Option<Note> {
+        let Cell::Pitch(pitch) = cells[0] else {
+            return None;
+        };
+        let mut note = Note::new(step as f32, pitch as i8, DEFAULT_VELOCITY);
+        for (idx, cell) in cells[1..].iter().enumerate() {
+            let params = &mut note.parameters;
+            match *cell {
+                Cell::Param(value) if idx == 0 => params.harmonics = Some(scale(value)),
+                Cell::Param(value) => params.timbre = Some(scale(value)),
+                Cell::Velocity(velocity) => {
+                    note.velocity = (velocity as i32 * 127 / MAX_VALUE as i32) as i8
+                }
+                Cell::Fx(Fx::Engine(engine)) => params.engine = Some(engine as f32),
+                Cell::Fx(Fx::Morph(morph)) => params.morph = Some(scale(morph)),
+                Cell::Empty | Cell::Pitch(_) => {}
             }
-        } else {
-            None
         }
+        Some(note)
     }
+}
 
-    fn get_pitch(input: &str, len: usize, pitch_map: &HashMap<String, i32>) -> Option<i32> {
-        if input.len() >= len && input[0..len].chars().all(|c| c.is_alphabetic() || c == '#') {
-            let note = input[0..len].to_uppercase();
-            let octave = input[len..].parse::<i32>().unwrap_or(2);
-            return pitch_map.get(&note).map(|n| n + octave * 12);
-        }
-        None
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Params;
+
+    fn insert(input: &str) -> Vec<Command> {
+        vec![Command::Insert {
+            x: 0,
+            y: 0,
+            cell: Cell::parse(input, CellKind::Pitch).unwrap(),
+        }]
     }
 
-    fn parse_pitch(input: &str) -> Option<i32> {
-        let mut pitch_map = HashMap::new();
-        for (idx, pitch) in PITCHES.iter().enumerate() {
-            pitch_map.insert(pitch.to_string(), (idx + 12) as i32);
+    #[test]
+    fn test_undo_tree() {
+        let mut history = History::new();
+        history.apply(insert("C3"));
+        history.apply(insert("D3"));
+        history.undo();
+        // a new change branches off instead of dropping D3
+        history.apply(insert("E3"));
+        assert_eq!(history.get_grid()[0][0].to_string(), "E3");
+
+        history.undo();
+        history.redo();
+        assert_eq!(history.get_grid()[0][0].to_string(), "E3");
+
+        // g- walks back through D3 even though it is on another branch
+        history.earlier(1);
+        assert_eq!(history.get_grid()[0][0].to_string(), "D3");
+        history.undo();
+        assert_eq!(history.get_grid()[0][0].to_string(), "C3");
+        history.redo();
+        assert_eq!(history.get_grid()[0][0].to_string(), "D3");
+        history.later(5);
+        assert_eq!(history.get_grid()[0][0].to_string(), "E3");
+        history.earlier(3);
+        assert_eq!(history.get_grid()[0][0], Cell::Empty);
+    }
+
+    #[test]
+    fn test_transactions() {
+        let mut history = History::new();
+        history.begin();
+        history.apply(insert("C3"));
+        history.begin();
+        history.apply(vec![Command::Resize { len: 4 }]);
+        history.commit();
+        history.apply(vec![Command::Delete { x: 0, y: 0 }]);
+        history.commit();
+        history.apply(insert("D3"));
+        assert_eq!(history.revisions.len(), 3);
+
+        history.undo();
+        assert_eq!(history.get_grid()[0][0], Cell::Empty);
+        assert_eq!(history.get_grid()[0].len(), 4);
+        history.undo();
+        assert_eq!(history.get_grid()[0].len(), INITIAL_STEP_COUNT);
+    }
+
+    #[test]
+    fn test_resize_restores_cells() {
+        let mut history = History::new();
+        history.apply(vec![Command::Insert {
+            x: 4,
+            y: 12,
+            cell: Cell::Param(50),
+        }]);
+        history.apply(vec![Command::Resize { len: 8 }]);
+        assert_eq!(history.get_grid()[4].len(), 8);
+        history.undo();
+        assert_eq!(history.get_grid()[4][12], Cell::Param(50));
+        // edits that change nothing are not recorded
+        history.apply(vec![Command::Resize {
+            len: INITIAL_STEP_COUNT,
+        }]);
+        assert_eq!(history.revisions.len(), 3);
+    }
+
+    #[test]
+    fn test_batch_sees_earlier_commands() {
+        let mut history = History::new();
+        let len = history.get_grid()[0].len();
+        let c3 = Cell::parse("C3", CellKind::Pitch).unwrap();
+        // the insert lands in a row the resize before it creates, and the
+        // delete clears what the insert before it wrote
+        history.apply(vec![
+            Command::Resize { len: len + 4 },
+            Command::Insert {
+                x: 0,
+                y: len + 1,
+                cell: c3,
+            },
+            Command::Insert {
+                x: 0,
+                y: 0,
+                cell: c3,
+            },
+            Command::Delete { x: 0, y: 0 },
+        ]);
+        assert_eq!(history.get_grid()[0][len + 1], c3);
+        assert_eq!(history.get_grid()[0][0], Cell::Empty);
+        history.undo();
+        assert_eq!(history.get_grid()[0].len(), len);
+        history.redo();
+        assert_eq!(history.get_grid()[0][len + 1], c3);
+        assert_eq!(history.get_grid()[0][0], Cell::Empty);
+    }
+
+    #[test]
+    fn test_prune() {
+        let mut history = History::new();
+        for step in 0..MAX_REVISIONS + 10 {
+            history.apply(vec![Command::Insert {
+                x: 0,
+                y: step % INITIAL_STEP_COUNT,
+                cell: Cell::Param((step % 100) as u8),
+            }]);
         }
+        assert!(history.revisions.len() <= MAX_REVISIONS);
+        let grid = history.get_grid().clone();
 
-        Self::get_pitch(input, 2, &pitch_map)
-            .or_else(|| Self::get_pitch(input, 1, &pitch_map))
-            .or_else(|| Self::get_pitch(input, 0, &pitch_map))
+        // undo everything that's left and back again
+        history.earlier(MAX_REVISIONS);
+        assert_eq!(history.revisions[history.pos].parent, None);
+        history.later(MAX_REVISIONS);
+        assert_eq!(*history.get_grid(), grid);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_earlier_by_time() {
+        let start = UNIX_EPOCH + Duration::from_secs(1000);
+        let mut history = History::new();
+        history.revisions[0].time = start;
+        history.apply_at(insert("C3"), start + Duration::from_secs(10));
+        history.apply_at(insert("D3"), start + Duration::from_secs(40));
+        history.apply_at(insert("E3"), start + Duration::from_secs(60));
+
+        history.earlier_by(Duration::from_secs(30));
+        assert_eq!(history.get_grid()[0][0].to_string(), "C3");
+        history.later_by(Duration::from_secs(35));
+        assert_eq!(history.get_grid()[0][0].to_string(), "D3");
+        history.earlier_by(Duration::from_secs(3600));
+        assert_eq!(history.get_grid()[0][0], Cell::Empty);
+    }
 
     #[test]
-    fn test_parse_input() {
-        assert_eq!(
-            History::parse_input(
-                &vec!["C0".to_string(), "50".to_string(), "50".to_string()],
-                0
-            ),
-            Some(Note {
-                timestamp: 0.0,
-                pitch: 12,
-                velocity: 100,
-                parameters: Params {
-                    engine: None,
-                    harmonics: Some(0.5),
-                    morph: Some(0.5),
-                    timbre: None,
-                }
-            })
-        );
-        assert_eq!(
-            History::parse_input(
-                &vec!["C#0".to_string(), "50".to_string(), "50".to_string()],
-                1
-            ),
-            Some(Note {
-                timestamp: 1.0,
-                pitch: 13,
-                velocity: 100,
-                parameters: Params {
-                    engine: None,
-                    harmonics: Some(0.5),
-                    morph: Some(0.5),
-                    timbre: None,
-                }
-            })
-        );
+    fn test_undo_file_roundtrip() {
+        let mut history = History::new();
+        history.apply(insert("C3"));
+        history.apply(insert("D3"));
+        history.apply(vec![Command::Resize { len: 4 }]);
+        history.undo();
+        history.undo();
+
+        let path = std::env::temp_dir().join(format!("bl8-undo-{}", std::process::id()));
+        history.save(&path).unwrap();
+        let mut loaded = History::new();
+        loaded.apply(insert("C3"));
+        loaded.load(&path).unwrap();
+
+        assert_eq!(loaded.revisions.len(), 4);
+        loaded.redo();
+        assert_eq!(loaded.get_grid()[0][0].to_string(), "D3");
+        loaded.redo();
+        assert_eq!(loaded.get_grid()[0].len(), 4);
+
+        let mut mismatched = History::new();
+        assert!(mismatched.load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_undo_tree() {
+        let undo = |revisions: &str| {
+            let grid = grid_to_text(&History::new().grid);
+            History::parse_undo(&format!("# bl8 undo\ncurrent 0\n\n{}{}", grid, revisions))
+        };
+        assert!(undo("\nrevision - 1 0\n\nrevision 0 - 0\n").is_ok());
+        // a root with a parent, a revision that is its own parent, a cycle,
+        // and redo pointing at a revision that isn't a child
+        assert!(undo("\nrevision 0 - 0\n").is_err());
+        assert!(undo("\nrevision - - 0\n\nrevision 1 - 0\n").is_err());
+        assert!(undo("\nrevision - - 0\n\nrevision 2 - 0\n\nrevision 1 - 0\n").is_err());
+        assert!(undo("\nrevision - 2 0\n\nrevision 0 - 0\n\nrevision 1 - 0\n").is_err());
+        assert!(undo("\nrevision - - 0\n\nrevision - - 0\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_undo_replays_tree() {
+        // the current revision is 2, the pattern only grew in revision 1
+        let undo = |first: &str, more: &str| {
+            let grid = grid_to_text(&History::new().grid);
+            let revisions = format!(
+                "\nrevision - 1 0\n\nrevision 0 2 0\n{}\nrevision 1 - 0\n+ resize 16\n- resize 32\n{}",
+                first, more
+            );
+            History::parse_undo(&format!("# bl8 undo\ncurrent 2\n\n{}{}", grid, revisions))
+        };
+        let first = "+ resize 32\n+ insert 0 20 C3\n- delete 0 20\n- resize 16\n";
+        assert!(undo(first, "").is_ok());
+        // steps past the end of the pattern as it was in revision 1, and in
+        // a branch off the root
+        assert!(undo(
+            "+ resize 32\n+ insert 0 40 C3\n- delete 0 40\n- resize 16\n",
+            ""
+        )
+        .is_err());
+        assert!(undo(
+            "+ insert 0 20 C3\n- delete 0 20\n+ resize 32\n- resize 16\n",
+            ""
+        )
+        .is_err());
+        let branch = "\nrevision 0 - 0\n+ insert 0 20 C3\n- delete 0 20\n";
+        assert!(undo(first, branch).is_err());
+        // an undo that doesn't bring back the length of the revision before
+        let branch = "\nrevision 0 - 0\n+ resize 32\n- delete 0 20\n";
+        assert!(undo(first, branch).is_err());
+    }
+
+    #[test]
+    fn test_parse_command() {
         assert_eq!(
-            History::parse_input(
-                &vec!["C1".to_string(), "50".to_string(), "50".to_string()],
-                1
-            ),
-            Some(Note {
-                timestamp: 1.0,
-                pitch: 24,
-                velocity: 100,
-                parameters: Params {
-                    engine: None,
-                    harmonics: Some(0.5),
-                    morph: Some(0.5),
-                    timbre: None,
-                }
-            })
+            History::parse_command("insert 3 4 C3").unwrap(),
+            Command::Insert {
+                x: 3,
+                y: 4,
+                cell: Cell::Pitch(48)
+            }
         );
+        assert!(History::parse_command("insert 99 0 C3").is_err());
+        assert!(History::parse_command("delete 0 500").is_err());
+        assert!(History::parse_command("resize 0").is_err());
+        assert!(History::parse_command("resize 129").is_err());
+        assert!(History::parse_command("resize 128").is_ok());
+    }
+
+    #[test]
+    fn test_to_note() {
+        let note = History::to_note([Cell::Pitch(12), Cell::Param(50), Cell::Velocity(99)], 3);
         assert_eq!(
-            History::parse_input(
-                &vec!["C".to_string(), "50".to_string(), "50".to_string()],
-                0
-            ),
+            note,
             Some(Note {
-                timestamp: 0.0,
-                pitch: 36,
-                velocity: 100,
+                timestamp: 3.0,
+                pitch: 12,
+                velocity: 127,
                 parameters: Params {
                     engine: None,
                     harmonics: Some(0.5),
-                    morph: Some(0.5),
+                    morph: None,
                     timbre: None,
                 }
             })
         );
+        let note = History::to_note(
+            [Cell::Pitch(60), Cell::Fx(Fx::Engine(4)), Cell::Param(25)],
+            0,
+        )
+        .unwrap();
+        assert_eq!(note.parameters.engine, Some(4.0));
+        assert_eq!(note.parameters.timbre, Some(0.25));
         assert_eq!(
-            History::parse_input(
-                &vec!["D".to_string(), "50".to_string(), "50".to_string()],
-                0
-            ),
-            Some(Note {
-                timestamp: 0.0,
-                pitch: 38,
-                velocity: 100,
-                parameters: Params {
-                    engine: None,
-                    harmonics: Some(0.5),
-                    morph: Some(0.5),
-                    timbre: None,
-                }
-            })
+            History::to_note([Cell::Empty, Cell::Param(50), Cell::Empty], 0),
+            None
         );
     }
 }
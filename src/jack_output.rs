@@ -0,0 +1,157 @@
+use anyhow::{Context, Result};
+use cpal::{BufferSize, SampleRate, StreamConfig, StreamError};
+use crossbeam::channel::*;
+use jack::{
+    AsyncClient, AudioOut, Client, ClientOptions, ClientStatus, NotificationHandler, Port,
+    ProcessHandler, ProcessScope, TransportState,
+};
+
+use crate::audio::Driver;
+use crate::engine::{Control, SEQ_TRACK_COUNT, TRACK_NAMES};
+
+const CLIENT_NAME: &str = "bl8";
+const MASTER_PORTS: [(&str, &str); 2] = [
+    ("master_l", "system:playback_1"),
+    ("master_r", "system:playback_2"),
+];
+
+// a jack client with the mix on a stereo pair and every track on a port of
+// its own, for recording the tracks separately into a daw
+pub struct JackOutput {
+    _client: AsyncClient<Notifications, Process>,
+}
+
+impl JackOutput {
+    // the client is connected first, the engine needs to know its rate
+    pub fn connect() -> Result<(Client, StreamConfig)> {
+        let (client, _) = Client::new(CLIENT_NAME, ClientOptions::NO_START_SERVER)
+            .context("can't connect to jack")?;
+        let config = StreamConfig {
+            channels: MASTER_PORTS.len() as u16,
+            sample_rate: SampleRate(client.sample_rate() as u32),
+            buffer_size: BufferSize::Fixed(client.buffer_size()),
+        };
+        Ok((client, config))
+    }
+
+    pub fn start(
+        client: Client,
+        driver: Driver,
+        transport: bool,
+        errors: Sender<StreamError>,
+    ) -> Result<JackOutput> {
+        let master = [
+            client.register_port(MASTER_PORTS[0].0, AudioOut)?,
+            client.register_port(MASTER_PORTS[1].0, AudioOut)?,
+        ];
+        let tracks = TRACK_NAMES
+            .iter()
+            .map(|name| client.register_port(name, AudioOut))
+            .collect::<Result<Vec<Port<AudioOut>>, jack::Error>>()?;
+        let master_names = master
+            .iter()
+            .map(|port| port.name())
+            .collect::<Result<Vec<String>, jack::Error>>()?;
+
+        let size = client.buffer_size() as usize;
+        let process = Process {
+            driver,
+            master,
+            tracks,
+            transport,
+            mix: vec![0.0; size],
+            frames: vec![[0.0; SEQ_TRACK_COUNT]; size],
+            next_frame: None,
+            bpm: None,
+        };
+        let client = client.activate_async(Notifications { errors }, process)?;
+        // the mix goes to the speakers, the tracks are left for patching
+        for (port, (_, playback)) in master_names.iter().zip(MASTER_PORTS) {
+            let _ = client.as_client().connect_ports_by_name(port, playback);
+        }
+        Ok(JackOutput { _client: client })
+    }
+}
+
+pub struct Notifications {
+    errors: Sender<StreamError>,
+}
+
+impl NotificationHandler for Notifications {
+    fn shutdown(&mut self, _: ClientStatus, _: &str) {
+        // handled like a lost device, the ui tries to connect again
+        let _ = self.errors.send(StreamError::DeviceNotAvailable);
+    }
+}
+
+pub struct Process {
+    driver: Driver,
+    master: [Port<AudioOut>; 2],
+    tracks: Vec<Port<AudioOut>>,
+    transport: bool,
+    // rendered before being copied to the ports, sized for jack's buffers
+    // so process never allocates
+    mix: Vec<f32>,
+    frames: Vec<[f32; SEQ_TRACK_COUNT]>,
+    // where the transport would be if nobody moved it
+    next_frame: Option<u64>,
+    bpm: Option<f64>,
+}
+
+impl Process {
+    // play while the transport rolls, jump along when it's moved and take
+    // over the tempo when a timebase master sets one
+    fn follow_transport(&mut self, client: &Client, frames: u64) {
+        let engine = &mut self.driver.engine;
+        let Ok(state) = client.transport().query() else {
+            return;
+        };
+        if state.state != TransportState::Rolling {
+            self.next_frame = None;
+            engine.set_running(false);
+            return;
+        }
+        let frame = state.pos.frame() as u64;
+        if self.next_frame != Some(frame) {
+            engine.locate(frame);
+        }
+        self.next_frame = Some(frame + frames);
+        engine.set_running(true);
+
+        if let Some(bbt) = state.pos.bbt() {
+            if bbt.bpm > 0.0 && self.bpm != Some(bbt.bpm) {
+                self.bpm = Some(bbt.bpm);
+                engine.control(Control::Bpm(bbt.bpm as f32));
+            }
+        }
+    }
+}
+
+impl ProcessHandler for Process {
+    fn process(&mut self, client: &Client, scope: &ProcessScope) -> jack::Control {
+        let frames = scope.n_frames() as usize;
+        if self.transport {
+            self.follow_transport(client, frames as u64);
+        }
+        let (mix, tracks) = (&mut self.mix[..frames], &mut self.frames[..frames]);
+        self.driver.render_tracks(mix, tracks);
+
+        for port in self.master.iter_mut() {
+            port.as_mut_slice(scope).copy_from_slice(mix);
+        }
+        for (track, port) in self.tracks.iter_mut().enumerate() {
+            for (out, frame) in port.as_mut_slice(scope).iter_mut().zip(tracks.iter()) {
+                *out = frame[track];
+            }
+        }
+        jack::Control::Continue
+    }
+
+    // jack calls this before process gets buffers of a new size, it is the
+    // one place on the audio thread that may allocate
+    fn buffer_size(&mut self, _: &Client, size: jack::Frames) -> jack::Control {
+        self.mix.resize(size as usize, 0.0);
+        self.frames.resize(size as usize, [0.0; SEQ_TRACK_COUNT]);
+        jack::Control::Continue
+    }
+}
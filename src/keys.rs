@@ -0,0 +1,507 @@
+use anyhow::{bail, Result};
+
+use crate::registers::Registers;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Motion {
+    Left,
+    Down,
+    Up,
+    Right,
+    FirstRow,
+    LastRow,
+    FirstColumn,
+    LastColumn,
+    NextTrack,
+    PrevTrack,
+    NextBeat,
+    PrevBeat,
+    NextMatch,
+    PrevMatch,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Action {
+    Move(Motion),
+    Undo,
+    Redo,
+    Earlier,
+    Later,
+    Delete,
+    Yank,
+    Paste,
+    Increment,
+    Decrement,
+    Interpolate,
+    Reverse,
+    Randomize,
+    // turn the parameter under the cursor while playing
+    TweakUp,
+    TweakDown,
+    Insert,
+    Visual,
+    Command,
+    Search,
+    Repeat,
+    Record(char),
+    Play(char),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct KeyCommand {
+    pub count: Option<usize>,
+    pub register: Option<char>,
+    pub action: Action,
+}
+
+#[derive(PartialEq, Debug)]
+pub enum Parsed {
+    Pending,
+    Invalid,
+    Complete(KeyCommand),
+}
+
+// counts beyond this are cut down to it, which is more than any pattern
+// needs and keeps something like 999999999p from running out of memory
+const MAX_COUNT: usize = 999;
+
+const MOTIONS: [(&str, Motion); 14] = [
+    ("h", Motion::Left),
+    ("j", Motion::Down),
+    ("k", Motion::Up),
+    ("l", Motion::Right),
+    ("gg", Motion::FirstRow),
+    ("G", Motion::LastRow),
+    ("0", Motion::FirstColumn),
+    ("$", Motion::LastColumn),
+    ("w", Motion::NextTrack),
+    ("b", Motion::PrevTrack),
+    ("}", Motion::NextBeat),
+    ("{", Motion::PrevBeat),
+    ("n", Motion::NextMatch),
+    ("N", Motion::PrevMatch),
+];
+
+const NORMAL_ACTIONS: [(&str, Action); 16] = [
+    ("u", Action::Undo),
+    ("r", Action::Redo),
+    ("g-", Action::Earlier),
+    ("g+", Action::Later),
+    ("x", Action::Delete),
+    ("y", Action::Yank),
+    ("p", Action::Paste),
+    ("+", Action::Increment),
+    ("-", Action::Decrement),
+    ("]", Action::TweakUp),
+    ("[", Action::TweakDown),
+    ("i", Action::Insert),
+    ("v", Action::Visual),
+    (":", Action::Command),
+    ("/", Action::Search),
+    (".", Action::Repeat),
+];
+
+const VISUAL_ACTIONS: [(&str, Action); 10] = [
+    ("y", Action::Yank),
+    ("d", Action::Delete),
+    ("x", Action::Delete),
+    ("p", Action::Paste),
+    ("+", Action::Increment),
+    ("-", Action::Decrement),
+    ("i", Action::Interpolate),
+    ("R", Action::Reverse),
+    ("?", Action::Randomize),
+    (":", Action::Command),
+];
+
+// the names keys are mapped to with :map and in the config file
+const ACTION_NAMES: [(&str, Action); 33] = [
+    ("left", Action::Move(Motion::Left)),
+    ("down", Action::Move(Motion::Down)),
+    ("up", Action::Move(Motion::Up)),
+    ("right", Action::Move(Motion::Right)),
+    ("first_row", Action::Move(Motion::FirstRow)),
+    ("last_row", Action::Move(Motion::LastRow)),
+    ("first_column", Action::Move(Motion::FirstColumn)),
+    ("last_column", Action::Move(Motion::LastColumn)),
+    ("next_track", Action::Move(Motion::NextTrack)),
+    ("prev_track", Action::Move(Motion::PrevTrack)),
+    ("next_beat", Action::Move(Motion::NextBeat)),
+    ("prev_beat", Action::Move(Motion::PrevBeat)),
+    ("next_match", Action::Move(Motion::NextMatch)),
+    ("prev_match", Action::Move(Motion::PrevMatch)),
+    ("undo", Action::Undo),
+    ("redo", Action::Redo),
+    ("earlier", Action::Earlier),
+    ("later", Action::Later),
+    ("delete", Action::Delete),
+    ("yank", Action::Yank),
+    ("paste", Action::Paste),
+    ("increment", Action::Increment),
+    ("decrement", Action::Decrement),
+    ("interpolate", Action::Interpolate),
+    ("reverse", Action::Reverse),
+    ("randomize", Action::Randomize),
+    ("tweak_up", Action::TweakUp),
+    ("tweak_down", Action::TweakDown),
+    ("insert", Action::Insert),
+    ("visual", Action::Visual),
+    ("command", Action::Command),
+    ("search", Action::Search),
+    ("repeat", Action::Repeat),
+];
+
+impl Action {
+    pub fn from_name(name: &str) -> Result<Action> {
+        match ACTION_NAMES.iter().find(|(action, _)| *action == name) {
+            Some(&(_, action)) => Ok(action),
+            None => bail!("unknown action: {}", name),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Record(_) => "record",
+            Action::Play(_) => "play",
+            action => ACTION_NAMES
+                .iter()
+                .find(|(_, named)| named == action)
+                .map_or("", |(name, _)| name),
+        }
+    }
+}
+
+// collects normal and visual mode keys until they form a complete command of
+// the form ["x][count]keys, e.g. "a4y or 3j or gg
+pub struct KeyParser {
+    pending: String,
+    // key sequences and what they do, starting out with the vim like defaults
+    normal: Vec<(String, Action)>,
+    visual: Vec<(String, Action)>,
+}
+
+impl KeyParser {
+    pub fn new() -> KeyParser {
+        let bindings = |actions: &[(&str, Action)]| {
+            MOTIONS
+                .iter()
+                .map(|&(keys, motion)| (keys.to_string(), Action::Move(motion)))
+                .chain(
+                    actions
+                        .iter()
+                        .map(|&(keys, action)| (keys.to_string(), action)),
+                )
+                .collect()
+        };
+        KeyParser {
+            pending: String::new(),
+            normal: bindings(&NORMAL_ACTIONS),
+            visual: bindings(&VISUAL_ACTIONS),
+        }
+    }
+
+    // bind a key sequence, replacing whatever it did before
+    pub fn map(&mut self, keys: &str, action: Action, visual: bool) -> Result<()> {
+        match keys.chars().next() {
+            None => bail!("no keys to map"),
+            // these would be read as a register or a count
+            Some('"' | '1'..='9') => bail!("can't map {}", keys),
+            Some('q' | '@') if !visual => bail!("q and @ are used for macros"),
+            Some(_) => {}
+        }
+        // a sequence that starts with another one could never be typed, the
+        // shorter one completes first
+        for (bound, _) in self.bindings(visual) {
+            if bound != keys && keys.starts_with(bound.as_str()) {
+                bail!("{} is already mapped, {} could never be typed", bound, keys);
+            }
+            if bound != keys && bound.starts_with(keys) {
+                bail!(
+                    "{} is already mapped, {} would stop it being typed",
+                    bound,
+                    keys
+                );
+            }
+        }
+        let bindings = self.bindings_mut(visual);
+        bindings.retain(|(bound, _)| bound != keys);
+        bindings.push((keys.to_string(), action));
+        Ok(())
+    }
+
+    // the bindings that differ from the defaults, as (keys, action) pairs
+    pub fn mappings(&self, visual: bool) -> Vec<(String, Action)> {
+        let defaults = KeyParser::new();
+        let defaults = defaults.bindings(visual);
+        self.bindings(visual)
+            .iter()
+            .filter(|binding| !defaults.contains(binding))
+            .cloned()
+            .collect()
+    }
+
+    fn bindings(&self, visual: bool) -> &Vec<(String, Action)> {
+        if visual {
+            &self.visual
+        } else {
+            &self.normal
+        }
+    }
+
+    fn bindings_mut(&mut self, visual: bool) -> &mut Vec<(String, Action)> {
+        if visual {
+            &mut self.visual
+        } else {
+            &mut self.normal
+        }
+    }
+
+    pub fn pending(&self) -> &str {
+        &self.pending
+    }
+
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+
+    pub fn push(&mut self, ch: char, visual: bool) -> Parsed {
+        self.pending.push(ch);
+        let parsed = self.parse(&self.pending, visual);
+        if parsed != Parsed::Pending {
+            self.pending.clear();
+        }
+        parsed
+    }
+
+    fn parse(&self, input: &str, visual: bool) -> Parsed {
+        let mut rest = input;
+        let mut register = None;
+        if let Some(after) = rest.strip_prefix('"') {
+            let mut chars = after.chars();
+            match chars.next() {
+                None => return Parsed::Pending,
+                Some(name) if Registers::is_valid(name) => register = Some(name),
+                Some(_) => return Parsed::Invalid,
+            }
+            rest = chars.as_str();
+        }
+
+        // a leading zero is a motion rather than the start of a count
+        let digits = if rest.starts_with('0') {
+            0
+        } else {
+            rest.find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len())
+        };
+        let (count, keys) = rest.split_at(digits);
+        if keys.is_empty() {
+            return Parsed::Pending;
+        }
+
+        // macros take the register they are recorded into as an argument
+        if !visual {
+            let mut chars = keys.chars();
+            match (chars.next(), chars.next(), chars.next()) {
+                (Some('q' | '@'), None, _) => return Parsed::Pending,
+                (Some('q'), Some(name), None) if name.is_ascii_lowercase() => {
+                    return Parsed::Complete(KeyCommand {
+                        count: None,
+                        register: None,
+                        action: Action::Record(name),
+                    });
+                }
+                (Some('@'), Some(name), None) if name.is_ascii_lowercase() || name == '@' => {
+                    return Parsed::Complete(KeyCommand {
+                        count: parse_count(count),
+                        register: None,
+                        action: Action::Play(name),
+                    });
+                }
+                (Some('q' | '@'), _, _) => return Parsed::Invalid,
+                _ => {}
+            }
+        }
+
+        let mut is_prefix = false;
+        for (seq, action) in self.bindings(visual) {
+            if seq == keys {
+                return Parsed::Complete(KeyCommand {
+                    count: parse_count(count),
+                    register,
+                    action: *action,
+                });
+            }
+            is_prefix |= seq.starts_with(keys);
+        }
+
+        if is_prefix {
+            Parsed::Pending
+        } else {
+            Parsed::Invalid
+        }
+    }
+}
+
+fn parse_count(digits: &str) -> Option<usize> {
+    // only digits get here, so parsing fails when the count overflows
+    if digits.is_empty() {
+        None
+    } else {
+        Some(digits.parse().unwrap_or(MAX_COUNT).min(MAX_COUNT))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_all(keys: &str, visual: bool) -> Parsed {
+        let mut parser = KeyParser::new();
+        let mut parsed = Parsed::Pending;
+        for ch in keys.chars() {
+            parsed = parser.push(ch, visual);
+        }
+        parsed
+    }
+
+    #[test]
+    fn test_counts_and_motions() {
+        assert_eq!(
+            parse_all("4j", false),
+            Parsed::Complete(KeyCommand {
+                count: Some(4),
+                register: None,
+                action: Action::Move(Motion::Down),
+            })
+        );
+        assert_eq!(
+            parse_all("12gg", false),
+            Parsed::Complete(KeyCommand {
+                count: Some(12),
+                register: None,
+                action: Action::Move(Motion::FirstRow),
+            })
+        );
+        assert_eq!(
+            parse_all("99999999999999999999999p", false),
+            Parsed::Complete(KeyCommand {
+                count: Some(MAX_COUNT),
+                register: None,
+                action: Action::Paste,
+            })
+        );
+        assert_eq!(
+            parse_all("0", false),
+            Parsed::Complete(KeyCommand {
+                count: None,
+                register: None,
+                action: Action::Move(Motion::FirstColumn),
+            })
+        );
+        assert_eq!(parse_all("1", false), Parsed::Pending);
+        assert_eq!(parse_all("g", false), Parsed::Pending);
+        assert_eq!(
+            parse_all("3g-", false),
+            Parsed::Complete(KeyCommand {
+                count: Some(3),
+                register: None,
+                action: Action::Earlier,
+            })
+        );
+        assert_eq!(
+            parse_all("5]", false),
+            Parsed::Complete(KeyCommand {
+                count: Some(5),
+                register: None,
+                action: Action::TweakUp,
+            })
+        );
+        assert_eq!(parse_all("gx", false), Parsed::Invalid);
+    }
+
+    #[test]
+    fn test_registers_and_modes() {
+        assert_eq!(
+            parse_all("\"a2y", false),
+            Parsed::Complete(KeyCommand {
+                count: Some(2),
+                register: Some('a'),
+                action: Action::Yank,
+            })
+        );
+        assert_eq!(parse_all("\"!", false), Parsed::Invalid);
+        assert_eq!(
+            parse_all("i", true),
+            Parsed::Complete(KeyCommand {
+                count: None,
+                register: None,
+                action: Action::Interpolate,
+            })
+        );
+        assert_eq!(parse_all("R", false), Parsed::Invalid);
+    }
+
+    #[test]
+    fn test_map() {
+        let mut parser = KeyParser::new();
+        parser
+            .map("é", Action::from_name("undo").unwrap(), false)
+            .unwrap();
+        parser.map("k", Action::Move(Motion::Down), false).unwrap();
+        let undo = KeyCommand {
+            count: Some(2),
+            register: None,
+            action: Action::Undo,
+        };
+        parser.push('2', false);
+        assert_eq!(parser.push('é', false), Parsed::Complete(undo));
+        assert_eq!(
+            parser.push('k', false),
+            Parsed::Complete(KeyCommand {
+                count: None,
+                register: None,
+                action: Action::Move(Motion::Down),
+            })
+        );
+        // visual mode keeps its own bindings
+        assert_eq!(parser.push('é', true), Parsed::Invalid);
+        assert_eq!(
+            parser.mappings(false),
+            vec![
+                ("é".to_string(), Action::Undo),
+                ("k".to_string(), Action::Move(Motion::Down))
+            ]
+        );
+
+        assert!(Action::from_name("dance").is_err());
+        assert!(parser.map("2x", Action::Undo, false).is_err());
+        assert!(parser.map("qq", Action::Undo, false).is_err());
+        // j completes before jk could be typed, and g would do the same to gg
+        assert!(parser.map("jk", Action::Undo, false).is_err());
+        assert!(parser.map("g", Action::Undo, false).is_err());
+        assert!(parser.map("gu", Action::Undo, false).is_ok());
+        assert_eq!(Action::Move(Motion::NextBeat).name(), "next_beat");
+    }
+
+    #[test]
+    fn test_macros() {
+        assert_eq!(parse_all("q", false), Parsed::Pending);
+        assert_eq!(
+            parse_all("qa", false),
+            Parsed::Complete(KeyCommand {
+                count: None,
+                register: None,
+                action: Action::Record('a'),
+            })
+        );
+        assert_eq!(
+            parse_all("3@@", false),
+            Parsed::Complete(KeyCommand {
+                count: Some(3),
+                register: None,
+                action: Action::Play('@'),
+            })
+        );
+        assert_eq!(parse_all("q1", false), Parsed::Invalid);
+    }
+}
@@ -0,0 +1,21 @@
+// every type is built with new(), like the rest of the modules expect
+#![allow(clippy::new_without_default)]
+
+pub mod app;
+pub mod audio;
+pub mod block;
+pub mod cell;
+pub mod commands;
+pub mod config;
+pub mod engine;
+pub mod export;
+pub mod history;
+#[cfg(feature = "jack")]
+pub mod jack_output;
+pub mod keys;
+pub mod limiter;
+pub mod project;
+pub mod recorder;
+pub mod registers;
+pub mod screen;
+pub mod utils;
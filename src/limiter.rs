@@ -1,5 +1,3 @@
-use crate::app::SAMPLE_RATE;
-
 /*
   adapted from https://www.musicdsp.org/en/latest/Filters/265-output-limiter-using-envelope-follower-in-c.html
   not actually sure if it works as it should
@@ -11,10 +9,10 @@ pub struct Limiter {
 }
 
 impl Limiter {
-    pub fn new(attack: f32, release: f32, threshold: f32) -> Self {
+    pub fn new(attack: f32, release: f32, threshold: f32, sample_rate: f32) -> Self {
         Self {
             threshold,
-            env_follower: EnvelopeFollower::new(attack, release),
+            env_follower: EnvelopeFollower::new(attack, release, sample_rate),
         }
     }
 
@@ -36,11 +34,11 @@ struct EnvelopeFollower {
 }
 
 impl EnvelopeFollower {
-    pub fn new(attack: f32, release: f32) -> Self {
+    pub fn new(attack: f32, release: f32, sample_rate: f32) -> Self {
         Self {
             // makes attack and release curves exponential?
-            attack: (0.01 as f32).powf(1.0 / (attack * SAMPLE_RATE * 0.001)),
-            release: (0.01 as f32).powf(1.0 / (release * SAMPLE_RATE * 0.001)),
+            attack: (0.01 as f32).powf(1.0 / (attack * sample_rate * 0.001)),
+            release: (0.01 as f32).powf(1.0 / (release * sample_rate * 0.001)),
             env: 0.0,
         }
     }
@@ -65,7 +63,7 @@ mod tests {
         let attack = 0.5;
         let release = 0.5;
         let threshold = 0.5;
-        let limiter = Limiter::new(attack, release, threshold);
+        let limiter = Limiter::new(attack, release, threshold, 48000.0);
 
         assert_eq!(limiter.threshold, 0.5);
     }
@@ -75,7 +73,7 @@ mod tests {
         let attack = 0.0;
         let release = 0.0;
         let threshold = 0.1;
-        let mut limiter = Limiter::new(attack, release, threshold);
+        let mut limiter = Limiter::new(attack, release, threshold, 48000.0);
 
         // should limit value
         assert_eq!(limiter.tick(1.0), 1.0);
@@ -90,7 +88,7 @@ mod tests {
     fn creates_new_envelope_follower() {
         let attack = 0.5;
         let release = 0.5;
-        let limiter = EnvelopeFollower::new(attack, release);
+        let limiter = EnvelopeFollower::new(attack, release, 48000.0);
 
         assert_eq!(limiter.attack, 0.82540417);
         assert_eq!(limiter.release, 0.82540417);
@@ -1,14 +1,11 @@
 use log::LevelFilter;
 use simplelog::*;
-use std::{fs::File, result::Result};
+use std::{env, fs::File, result::Result};
 
-mod app;
-use app::App;
+use bl8_tui_rs::app::App;
 
-mod engine;
-mod history;
-mod limiter;
-mod utils;
+const USAGE: &str =
+    "usage: bl8 [--audio cpal|null|jack] [--device NAME] [--sample-rate HZ] [--buffer-size FRAMES] [--transport on|off]";
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     // init logging
@@ -16,6 +13,29 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     WriteLogger::init(LevelFilter::Info, Config::default(), log_file).unwrap();
 
     let mut app = App::new();
+    app.load_config();
+
+    // command line options win over the config file
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let option = match arg.as_str() {
+            "--audio" => "audio",
+            "--device" => "device",
+            "--sample-rate" => "samplerate",
+            "--buffer-size" => "buffersize",
+            "--transport" => "transport",
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ => return Err(format!("unknown option: {}\n{}", arg, USAGE).into()),
+        };
+        let value = args
+            .next()
+            .ok_or_else(|| format!("{} requires a value", arg))?;
+        app.set_option(option, &value)?;
+    }
+
     app.run()?;
     Ok(())
 }
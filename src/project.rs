@@ -0,0 +1,204 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::fs;
+use std::path::Path;
+
+use crate::cell::{Cell, CellKind};
+use crate::engine::{
+    DEFAULT_BPM, DEFAULT_ENGINE, ENGINE_COUNT, MAX_BPM, MAX_STEP_COUNT, MIN_BPM, PARAMS,
+    PARAM_COUNT, SEQ_TRACK_COUNT,
+};
+use crate::history::Grid;
+
+const HEADER: &str = "# bl8 project";
+// empty cells are written as a dot so every row has the same number of fields
+const EMPTY_FIELD: &str = ".";
+// glided parameters go by their initials, a track without any is a dash
+const NO_GLIDE: &str = "-";
+
+// everything that is saved with :w and restored with :e
+#[derive(Debug, PartialEq)]
+pub struct Project {
+    pub bpm: f32,
+    pub engines: [usize; SEQ_TRACK_COUNT],
+    pub glide: [[bool; PARAM_COUNT]; SEQ_TRACK_COUNT],
+    pub grid: Grid,
+}
+
+impl Project {
+    pub fn load(path: &Path) -> Result<Project> {
+        let text =
+            fs::read_to_string(path).with_context(|| format!("can't read {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("can't load {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.serialize()).with_context(|| format!("can't write {}", path.display()))
+    }
+
+    fn serialize(&self) -> String {
+        let mut text = format!("{}\nbpm {}\nengines", HEADER, self.bpm);
+        for engine in self.engines {
+            text.push_str(&format!(" {}", engine));
+        }
+        // only written when used, so older versions still read the file
+        if self.glide.iter().flatten().any(|&glide| glide) {
+            text.push_str("\nglide");
+            for glide in self.glide {
+                let initials = PARAMS
+                    .iter()
+                    .zip(glide)
+                    .filter(|&(_, glide)| glide)
+                    .map(|(param, _)| &param.name()[..1])
+                    .collect::<String>();
+                if initials.is_empty() {
+                    text.push_str(&format!(" {}", NO_GLIDE));
+                } else {
+                    text.push_str(&format!(" {}", initials));
+                }
+            }
+        }
+        text.push_str("\n\n");
+        text.push_str(&grid_to_text(&self.grid));
+        text
+    }
+
+    fn parse(text: &str) -> Result<Project> {
+        let mut lines = text.lines();
+        if lines.next() != Some(HEADER) {
+            bail!("not a bl8 project");
+        }
+
+        let mut bpm = DEFAULT_BPM;
+        let mut engines = [DEFAULT_ENGINE; SEQ_TRACK_COUNT];
+        let mut glide = [[false; PARAM_COUNT]; SEQ_TRACK_COUNT];
+        // settings come first, followed by an empty line and the pattern
+        for line in lines.by_ref().take_while(|line| !line.trim().is_empty()) {
+            let mut fields = line.split_whitespace();
+            match fields.next() {
+                Some("bpm") => {
+                    bpm = fields
+                        .next()
+                        .and_then(|bpm| bpm.parse().ok())
+                        .filter(|bpm| (MIN_BPM..=MAX_BPM).contains(bpm))
+                        .ok_or_else(|| anyhow!("invalid bpm: {}", line))?;
+                }
+                Some("engines") => {
+                    for (engine, field) in engines.iter_mut().zip(fields) {
+                        *engine = field
+                            .parse()
+                            .ok()
+                            .filter(|&engine| engine < ENGINE_COUNT)
+                            .ok_or_else(|| anyhow!("invalid engine: {}", field))?;
+                    }
+                }
+                Some("glide") => {
+                    for (glide, field) in glide.iter_mut().zip(fields) {
+                        for initial in field.chars().filter(|_| field != NO_GLIDE) {
+                            let idx = PARAMS
+                                .iter()
+                                .position(|param| param.name().starts_with(initial))
+                                .ok_or_else(|| anyhow!("invalid glide: {}", field))?;
+                            glide[idx] = true;
+                        }
+                    }
+                }
+                _ => bail!("unknown setting: {}", line),
+            }
+        }
+
+        let grid = grid_from_lines(lines.filter(|line| !line.trim().is_empty()))?;
+
+        Ok(Project {
+            bpm,
+            engines,
+            glide,
+            grid,
+        })
+    }
+}
+
+// one line per step with tab separated cells, shared with the undo file
+pub fn grid_to_text(grid: &Grid) -> String {
+    let mut text = String::new();
+    let rows = grid.first().map_or(0, |column| column.len());
+    for row in 0..rows {
+        let cells = grid
+            .iter()
+            .map(|column| match column[row] {
+                Cell::Empty => EMPTY_FIELD.to_string(),
+                cell => cell.to_string(),
+            })
+            .collect::<Vec<String>>();
+        text.push_str(&cells.join("\t"));
+        text.push('\n');
+    }
+    text
+}
+
+pub fn grid_from_lines<'a>(lines: impl Iterator<Item = &'a str>) -> Result<Grid> {
+    let mut grid: Grid = vec![vec![]; SEQ_TRACK_COUNT * 3];
+    for line in lines {
+        if grid[0].len() == MAX_STEP_COUNT {
+            bail!("more than {} steps", MAX_STEP_COUNT);
+        }
+        let cells = line.split('\t').collect::<Vec<&str>>();
+        if cells.len() != grid.len() {
+            bail!("expected {} cells per row: {}", grid.len(), line);
+        }
+        for (col, (column, cell)) in grid.iter_mut().zip(cells).enumerate() {
+            column.push(match cell.trim() {
+                EMPTY_FIELD => Cell::Empty,
+                cell => Cell::parse(cell, CellKind::of(col))
+                    .with_context(|| format!("step {}", column.len() + 1))?,
+            });
+        }
+    }
+    if grid[0].is_empty() {
+        bail!("project has no steps");
+    }
+    Ok(grid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::INITIAL_STEP_COUNT;
+
+    #[test]
+    fn test_roundtrip() {
+        let mut grid = vec![vec![Cell::Empty; INITIAL_STEP_COUNT]; SEQ_TRACK_COUNT * 3];
+        grid[0][0] = Cell::Pitch(48);
+        grid[1][0] = Cell::Param(50);
+        grid[5][15] = Cell::Velocity(80);
+        let project = Project {
+            bpm: 98.5,
+            engines: [1, 1, 1, 4, 5, 6, 7, 8],
+            glide: [[false; PARAM_COUNT]; SEQ_TRACK_COUNT],
+            grid,
+        };
+        assert!(!project.serialize().contains("glide"));
+        assert_eq!(Project::parse(&project.serialize()).unwrap(), project);
+
+        let mut project = project;
+        project.glide[3] = [false, true, true];
+        assert!(project.serialize().contains("\nglide - - - tm - - - -\n"));
+        assert_eq!(Project::parse(&project.serialize()).unwrap(), project);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Project::parse("hello").is_err());
+        assert!(Project::parse("# bl8 project\nbpm fast\n\n").is_err());
+        assert!(Project::parse("# bl8 project\nbpm NaN\n\n").is_err());
+        assert!(Project::parse("# bl8 project\nbpm 1000\n\n").is_err());
+        assert!(Project::parse("# bl8 project\nengines 1 99\n\n").is_err());
+        assert!(Project::parse("# bl8 project\nglide - x\n\n").is_err());
+        assert!(Project::parse("# bl8 project\nbpm 120\n\nC3\t50\n").is_err());
+        let row = ["C3"; SEQ_TRACK_COUNT * 3].join("\t");
+        assert!(Project::parse(&format!("# bl8 project\n\n{}\n", row)).is_err());
+        let row = [EMPTY_FIELD; SEQ_TRACK_COUNT * 3].join("\t") + "\n";
+        let text = format!("# bl8 project\n\n{}", row.repeat(MAX_STEP_COUNT));
+        assert!(Project::parse(&text).is_ok());
+        assert!(Project::parse(&(text + &row)).is_err());
+    }
+}
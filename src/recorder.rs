@@ -0,0 +1,127 @@
+use anyhow::{Context, Result};
+use cpal::StreamConfig;
+use hound::{SampleFormat, WavSpec, WavWriter};
+use rtrb::{Consumer, Producer, RingBuffer};
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+// seconds of audio the writer may fall behind before samples are dropped
+const BUFFER_LENGTH: usize = 2;
+const WRITER_INTERVAL: Duration = Duration::from_millis(20);
+
+// the audio thread's end of a recording, it never blocks or allocates
+pub struct Tap {
+    producer: Producer<f32>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Tap {
+    // whole buffers go in or none of it, so channels never get out of step
+    #[inline]
+    pub fn write(&mut self, samples: &[f32]) {
+        match self.producer.write_chunk_uninit(samples.len()) {
+            Ok(chunk) => {
+                chunk.fill_from_iter(samples.iter().copied());
+            }
+            Err(_) => {
+                self.dropped
+                    .fetch_add(samples.len() as u64, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+// writes what the tap receives to a wav file on a thread of its own, the
+// recording ends once the tap is dropped or it is finished
+pub struct Recorder {
+    path: PathBuf,
+    channels: u16,
+    dropped: Arc<AtomicU64>,
+    stop: Arc<AtomicBool>,
+    writer: JoinHandle<Result<u64>>,
+}
+
+impl Recorder {
+    pub fn start(path: &Path, config: &StreamConfig) -> Result<(Recorder, Tap)> {
+        let spec = WavSpec {
+            channels: config.channels,
+            sample_rate: config.sample_rate.0,
+            bits_per_sample: 32,
+            sample_format: SampleFormat::Float,
+        };
+        let wav = WavWriter::create(path, spec)
+            .with_context(|| format!("can't write {}", path.display()))?;
+        let capacity = config.sample_rate.0 as usize * config.channels as usize * BUFFER_LENGTH;
+        let (producer, consumer) = RingBuffer::new(capacity);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let writer_stop = stop.clone();
+        let recorder = Recorder {
+            path: path.to_path_buf(),
+            channels: config.channels,
+            dropped: dropped.clone(),
+            stop,
+            writer: thread::spawn(move || write(wav, consumer, &writer_stop)),
+        };
+        Ok((recorder, Tap { producer, dropped }))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // waits for the writer to empty the buffer, returns the frames written
+    // and the ones dropped. it doesn't wait for the audio thread to let go of
+    // the tap, a stalled stream never would
+    pub fn finish(self) -> Result<(u64, u64)> {
+        self.stop.store(true, Ordering::Relaxed);
+        let samples = self
+            .writer
+            .join()
+            .map_err(|_| anyhow::anyhow!("the recording thread panicked"))?
+            .with_context(|| format!("can't write {}", self.path.display()))?;
+        let channels = self.channels.max(1) as u64;
+        let dropped = self.dropped.load(Ordering::Relaxed);
+        Ok((samples / channels, dropped / channels))
+    }
+}
+
+fn write(
+    mut wav: WavWriter<BufWriter<File>>,
+    mut consumer: Consumer<f32>,
+    stop: &AtomicBool,
+) -> Result<u64> {
+    let mut written = 0;
+    loop {
+        let slots = consumer.slots();
+        if slots == 0 {
+            // the tap writes whole buffers, so stopping once it's empty
+            // never leaves channels out of step
+            let done = consumer.is_abandoned() || stop.load(Ordering::Relaxed);
+            if done && consumer.is_empty() {
+                break;
+            }
+            thread::sleep(WRITER_INTERVAL);
+            continue;
+        }
+        let chunk = consumer.read_chunk(slots)?;
+        let (first, second) = chunk.as_slices();
+        for &sample in first.iter().chain(second) {
+            wav.write_sample(sample)?;
+        }
+        chunk.commit_all();
+        written += slots as u64;
+    }
+    wav.finalize()?;
+    Ok(written)
+}
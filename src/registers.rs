@@ -0,0 +1,118 @@
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use std::collections::HashMap;
+use std::io::{stdout, Result, Write};
+
+use crate::block::Block;
+use crate::cell::Cell;
+
+pub const UNNAMED: char = '"';
+pub const CLIPBOARD: char = '+';
+
+pub struct Registers {
+    registers: HashMap<char, Block>,
+}
+
+impl Registers {
+    pub fn new() -> Registers {
+        Registers {
+            registers: HashMap::new(),
+        }
+    }
+
+    pub fn is_valid(name: char) -> bool {
+        name.is_ascii_lowercase() || name == UNNAMED || name == CLIPBOARD || name == '*'
+    }
+
+    pub fn get(&self, name: Option<char>) -> Option<&Block> {
+        self.registers.get(&Self::resolve(name))
+    }
+
+    pub fn set(&mut self, name: Option<char>, block: Block) -> Result<()> {
+        // like vim, every yank or delete also ends up in the unnamed register
+        let name = Self::resolve(name);
+        if name == CLIPBOARD {
+            Self::copy_to_clipboard(&block)?;
+        }
+        self.registers.insert(UNNAMED, block.clone());
+        self.registers.insert(name, block);
+        Ok(())
+    }
+
+    pub fn list(&self) -> Vec<String> {
+        let mut names = self.registers.keys().copied().collect::<Vec<char>>();
+        names.sort();
+        names
+            .into_iter()
+            .map(|name| {
+                let block = &self.registers[&name];
+                let rows = block.first().map_or(0, |column| column.len());
+                let text = Self::to_text(block).replace('\n', " | ");
+                format!("\"{}  {}x{}  {}", name, block.len(), rows, text)
+            })
+            .collect()
+    }
+
+    fn resolve(name: Option<char>) -> char {
+        match name {
+            Some('*') => CLIPBOARD,
+            Some(name) => name,
+            None => UNNAMED,
+        }
+    }
+
+    pub fn to_text(block: &Block) -> String {
+        // one line per step, cells separated by tabs
+        let rows = block.first().map_or(0, |column| column.len());
+        (0..rows)
+            .map(|row| {
+                block
+                    .iter()
+                    .map(|column| match column[row] {
+                        Cell::Empty => String::new(),
+                        cell => cell.to_string(),
+                    })
+                    .collect::<Vec<String>>()
+                    .join("\t")
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    fn copy_to_clipboard(block: &Block) -> Result<()> {
+        // OSC 52 asks the terminal to set the system clipboard, which also
+        // works over ssh
+        let mut stdout = stdout();
+        let encoded = STANDARD.encode(Self::to_text(block));
+        write!(stdout, "\x1b]52;c;{}\x07", encoded)?;
+        stdout.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_named_registers() {
+        let mut registers = Registers::new();
+        let block = vec![vec![Cell::Pitch(48), Cell::Empty]];
+        registers.set(Some('a'), block.clone()).unwrap();
+
+        assert_eq!(registers.get(Some('a')), Some(&block));
+        assert_eq!(registers.get(None), Some(&block));
+        assert_eq!(registers.get(Some('b')), None);
+        assert_eq!(
+            registers.list(),
+            vec!["\"\"  1x2  C3 | ", "\"a  1x2  C3 | "]
+        );
+    }
+
+    #[test]
+    fn test_to_text() {
+        let block = vec![
+            vec![Cell::Pitch(48), Cell::Empty],
+            vec![Cell::Param(50), Cell::Param(10)],
+        ];
+        assert_eq!(Registers::to_text(&block), "C3\t50\n\t10");
+    }
+}
@@ -0,0 +1,178 @@
+use crossterm::{
+    cursor, queue,
+    style::{ContentStyle, PrintStyledContent, StyledContent},
+    terminal,
+};
+use std::io::{Result, Write};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Glyph {
+    ch: char,
+    style: ContentStyle,
+}
+
+impl Glyph {
+    fn blank() -> Glyph {
+        Glyph {
+            ch: ' ',
+            style: ContentStyle::new(),
+        }
+    }
+}
+
+// frames are drawn into a back buffer, flush only writes the cells that differ
+// from what is already on the terminal
+pub struct Screen {
+    width: usize,
+    height: usize,
+    front: Vec<Glyph>,
+    back: Vec<Glyph>,
+    cursor: (u16, u16),
+    // forces a full redraw, e.g. after a resize
+    invalid: bool,
+}
+
+impl Screen {
+    pub fn new((width, height): (u16, u16)) -> Screen {
+        let (width, height) = (width as usize, height as usize);
+        Screen {
+            width,
+            height,
+            front: vec![Glyph::blank(); width * height],
+            back: vec![Glyph::blank(); width * height],
+            cursor: (0, 0),
+            invalid: true,
+        }
+    }
+
+    pub fn resize(&mut self, size: (u16, u16)) {
+        *self = Screen::new(size);
+    }
+
+    // the terminal was written to behind our back, repaint everything
+    pub fn invalidate(&mut self) {
+        self.invalid = true;
+    }
+
+    pub fn clear(&mut self) {
+        self.back.fill(Glyph::blank());
+    }
+
+    pub fn print(&mut self, x: u16, y: u16, text: &str, style: ContentStyle) {
+        let (x, y) = (x as usize, y as usize);
+        if y >= self.height {
+            return;
+        }
+        for (idx, ch) in text.chars().enumerate() {
+            if x + idx >= self.width {
+                break;
+            }
+            self.back[y * self.width + x + idx] = Glyph { ch, style };
+        }
+    }
+
+    // restyle cells that were already printed, e.g. to highlight a row
+    pub fn style(&mut self, x: u16, y: u16, len: usize, f: impl Fn(ContentStyle) -> ContentStyle) {
+        let (x, y) = (x as usize, y as usize);
+        if y >= self.height {
+            return;
+        }
+        for glyph in self.back[y * self.width..(y + 1) * self.width]
+            .iter_mut()
+            .skip(x)
+            .take(len)
+        {
+            glyph.style = f(glyph.style);
+        }
+    }
+
+    pub fn set_cursor(&mut self, x: u16, y: u16) {
+        self.cursor = (x, y);
+    }
+
+    pub fn flush(&mut self, out: &mut impl Write) -> Result<()> {
+        if self.invalid {
+            queue!(out, terminal::Clear(terminal::ClearType::All))?;
+            self.front.fill(Glyph::blank());
+        }
+        queue!(out, cursor::Hide)?;
+        for y in 0..self.height {
+            let row = y * self.width..(y + 1) * self.width;
+            let mut x = 0;
+            while x < self.width {
+                if self.back[row.start + x] == self.front[row.start + x] && !self.invalid {
+                    x += 1;
+                    continue;
+                }
+                // write runs of changed cells that share a style in one go
+                let style = self.back[row.start + x].style;
+                let start = x;
+                let mut text = String::new();
+                while x < self.width {
+                    let glyph = self.back[row.start + x];
+                    if glyph.style != style || (glyph == self.front[row.start + x] && !self.invalid)
+                    {
+                        break;
+                    }
+                    text.push(glyph.ch);
+                    x += 1;
+                }
+                queue!(
+                    out,
+                    cursor::MoveTo(start as u16, y as u16),
+                    PrintStyledContent(StyledContent::new(style, text))
+                )?;
+            }
+        }
+        queue!(
+            out,
+            cursor::MoveTo(self.cursor.0, self.cursor.1),
+            cursor::Show
+        )?;
+        out.flush()?;
+
+        self.front.copy_from_slice(&self.back);
+        self.invalid = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossterm::style::Stylize;
+
+    #[test]
+    fn test_flush_only_writes_changes() {
+        let mut screen = Screen::new((10, 2));
+        let mut out = vec![];
+        screen.print(0, 0, "C3  50", ContentStyle::new());
+        screen.flush(&mut out).unwrap();
+        assert!(String::from_utf8_lossy(&out).contains("C3  50"));
+
+        // an identical frame writes nothing but the cursor
+        out.clear();
+        screen.clear();
+        screen.print(0, 0, "C3  50", ContentStyle::new());
+        screen.flush(&mut out).unwrap();
+        assert!(!String::from_utf8_lossy(&out).contains("C3"));
+
+        out.clear();
+        screen.clear();
+        screen.print(0, 0, "C3  51", ContentStyle::new());
+        screen.style(0, 1, 4, |style| style.reverse());
+        screen.flush(&mut out).unwrap();
+        let written = String::from_utf8_lossy(&out);
+        assert!(written.contains('1'));
+        assert!(!written.contains("C3"));
+    }
+
+    #[test]
+    fn test_print_clips_to_screen() {
+        let mut screen = Screen::new((4, 1));
+        screen.print(2, 0, "HIHAT", ContentStyle::new());
+        screen.print(0, 3, "KICK", ContentStyle::new());
+        assert_eq!(screen.back[2].ch, 'H');
+        assert_eq!(screen.back[3].ch, 'I');
+    }
+}
@@ -0,0 +1,182 @@
+use std::time::{Duration, Instant};
+
+use bl8_tui_rs::audio::{self, Driver, NullOutput};
+use bl8_tui_rs::cell::Cell;
+use bl8_tui_rs::config::AudioConfig;
+use bl8_tui_rs::engine::{Control, Engine, Trigger, INITIAL_STEP_COUNT, SEQ_TRACK_COUNT};
+use bl8_tui_rs::history::{Grid, History};
+use bl8_tui_rs::recorder::Recorder;
+
+// 120 bpm sixteenths at 48 kHz
+const STEP_LENGTH: u64 = 6000;
+
+fn grid(notes: &[(usize, usize, u8)]) -> Grid {
+    let mut grid = vec![vec![Cell::Empty; INITIAL_STEP_COUNT]; SEQ_TRACK_COUNT * 3];
+    for &(track, step, pitch) in notes {
+        grid[track * 3][step] = Cell::Pitch(pitch);
+    }
+    grid
+}
+
+fn fired(triggers: &[Trigger]) -> Vec<(u64, usize, i8)> {
+    triggers
+        .iter()
+        .map(|trigger| (trigger.sample, trigger.track, trigger.note.pitch))
+        .collect()
+}
+
+#[test]
+fn notes_fire_on_their_step() {
+    let mut engine = Engine::new(48000.0);
+    engine.init();
+    engine.set_state(History::to_state(&grid(&[
+        (0, 0, 36),
+        (0, 4, 38),
+        (3, 2, 60),
+    ])));
+    let triggers = engine.watch();
+
+    for _ in 0..STEP_LENGTH * INITIAL_STEP_COUNT as u64 {
+        engine.tick();
+    }
+
+    assert_eq!(
+        fired(&triggers.try_iter().collect::<Vec<Trigger>>()),
+        vec![
+            (0, 0, 36),
+            (2 * STEP_LENGTH, 3, 60),
+            (4 * STEP_LENGTH, 0, 38)
+        ]
+    );
+}
+
+#[test]
+fn pattern_wraps_and_follows_tempo() {
+    let mut engine = Engine::new(48000.0);
+    engine.init();
+    let mut grid = grid(&[(1, 0, 40), (1, 3, 40)]);
+    for column in grid.iter_mut() {
+        column.truncate(4);
+    }
+    engine.set_state(History::to_state(&grid));
+    let triggers = engine.watch();
+
+    // the second pass through the pattern runs at double speed
+    for _ in 0..STEP_LENGTH * 4 {
+        engine.tick();
+    }
+    engine.control(Control::Bpm(240.0));
+    for _ in 0..STEP_LENGTH * 2 {
+        engine.tick();
+    }
+
+    let pass = 4 * STEP_LENGTH;
+    assert_eq!(
+        fired(&triggers.try_iter().collect::<Vec<Trigger>>()),
+        vec![
+            (0, 1, 40),
+            (3 * STEP_LENGTH, 1, 40),
+            (pass, 1, 40),
+            (pass + 3 * STEP_LENGTH / 2, 1, 40)
+        ]
+    );
+}
+
+#[test]
+fn null_output_plays_in_real_time() {
+    let config = audio::null_config(&AudioConfig::default());
+    let mut engine = Engine::new(config.sample_rate.0 as f32);
+    engine.init();
+    let triggers = engine.watch();
+    let (states, states_rx) = crossbeam::channel::unbounded();
+    let (_controls, controls_rx) = crossbeam::channel::unbounded();
+    let (_taps, taps_rx) = crossbeam::channel::unbounded();
+    states
+        .send(History::to_state(&grid(&[(0, 0, 36), (0, 2, 36)])))
+        .unwrap();
+
+    let start = Instant::now();
+    let output = NullOutput::start(
+        Driver::new(engine, states_rx, controls_rx, taps_rx),
+        &config,
+    );
+    let first = triggers.recv_timeout(Duration::from_secs(5)).unwrap();
+    let second = triggers.recv_timeout(Duration::from_secs(5)).unwrap();
+    drop(output);
+
+    assert_eq!((first.sample, first.step), (0, 0));
+    assert_eq!((second.sample, second.step), (2 * STEP_LENGTH, 2));
+    // two steps take a quarter of a second, less a buffer rendered ahead
+    assert!(start.elapsed() >= Duration::from_millis(200));
+}
+
+#[test]
+fn stops_and_locates_like_a_transport() {
+    let mut engine = Engine::new(48000.0);
+    engine.init();
+    engine.set_state(History::to_state(&grid(&[(0, 0, 36), (0, 8, 36)])));
+    let triggers = engine.watch();
+
+    engine.set_running(false);
+    for _ in 0..STEP_LENGTH {
+        engine.tick();
+    }
+    assert!(triggers.try_recv().is_err());
+
+    // landing right on a step plays it, landing within one doesn't, and song
+    // positions past the pattern wrap around
+    engine.set_running(true);
+    engine.locate(8 * STEP_LENGTH);
+    engine.tick();
+    engine.locate(16 * STEP_LENGTH + 10);
+    for _ in 0..STEP_LENGTH {
+        engine.tick();
+    }
+    assert_eq!(
+        fired(&triggers.try_iter().collect::<Vec<Trigger>>()),
+        vec![(STEP_LENGTH, 0, 36)]
+    );
+}
+
+#[test]
+fn null_output_is_recorded() {
+    let config = audio::null_config(&AudioConfig::default());
+    let engine = Engine::new(config.sample_rate.0 as f32);
+    let (_states, states_rx) = crossbeam::channel::unbounded();
+    let (_controls, controls_rx) = crossbeam::channel::unbounded();
+    let (taps, taps_rx) = crossbeam::channel::unbounded();
+    let path = std::env::temp_dir().join(format!("bl8-rec-{}.wav", std::process::id()));
+
+    let output = NullOutput::start(
+        Driver::new(engine, states_rx, controls_rx, taps_rx),
+        &config,
+    );
+    let (recorder, tap) = Recorder::start(&path, &config).unwrap();
+    taps.send(Some(tap)).unwrap();
+    std::thread::sleep(Duration::from_millis(200));
+    taps.send(None).unwrap();
+    let (frames, dropped) = recorder.finish().unwrap();
+    drop(output);
+
+    // whole buffers only, and the file holds every frame that was counted
+    assert_eq!(dropped, 0);
+    assert!(frames > 0 && frames % 512 == 0);
+    let wav = hound::WavReader::open(&path).unwrap();
+    assert_eq!(wav.spec().channels, 2);
+    assert_eq!(wav.duration() as u64, frames);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn recording_stops_without_the_audio_thread() {
+    // a stalled stream never lets go of its tap
+    let config = audio::null_config(&AudioConfig::default());
+    let path = std::env::temp_dir().join(format!("bl8-stalled-{}.wav", std::process::id()));
+    let (recorder, mut tap) = Recorder::start(&path, &config).unwrap();
+    tap.write(&[0.5; 64]);
+    let (frames, dropped) = recorder.finish().unwrap();
+    drop(tap);
+
+    assert_eq!((frames, dropped), (32, 0));
+    std::fs::remove_file(&path).unwrap();
+}